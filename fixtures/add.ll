@@ -0,0 +1,54 @@
+target datalayout = "e-i64:64-i128:128-v16:16-v32:32-n16:32:64"
+target triple = "nvptx64-nvidia-cuda"
+
+@table = addrspace(1) global [4 x i32] [i32 1, i32 2, i32 3, i32 4], align 4
+@cache = internal addrspace(3) global [256 x float] undef, align 4
+@scale = addrspace(4) constant float 2.0, align 4
+
+declare i32 @llvm.nvvm.read.ptx.sreg.tid.x()
+declare i32 @llvm.nvvm.read.ptx.sreg.ctaid.x()
+declare i32 @llvm.nvvm.read.ptx.sreg.ntid.x()
+declare void @llvm.nvvm.barrier0()
+
+define float @square(float %x) {
+  %y = fmul float %x, %x
+  ret float %y
+}
+
+define void @add(float* %a, float* %b, float* %c, i64 %n) {
+entry:
+  %tid = call i32 @llvm.nvvm.read.ptx.sreg.tid.x()
+  %ctaid = call i32 @llvm.nvvm.read.ptx.sreg.ctaid.x()
+  %ntid = call i32 @llvm.nvvm.read.ptx.sreg.ntid.x()
+  %base = mul i32 %ctaid, %ntid
+  %i32 = add i32 %base, %tid
+  %i = sext i32 %i32 to i64
+  %cmp = icmp slt i64 %i, %n
+  br i1 %cmp, label %body, label %exit
+body:
+  %pa = getelementptr float, float* %a, i64 %i
+  %pb = getelementptr float, float* %b, i64 %i
+  %va = load float, float* %pa
+  %vb = load float, float* %pb
+  %sum = fadd float %va, %vb
+  %sq = call float @square(float %sum)
+  %s = load float, float addrspace(4)* @scale
+  %scaled = fmul float %sq, %s
+  %tidx = and i32 %tid, 255
+  %sh = getelementptr [256 x float], [256 x float] addrspace(3)* @cache, i32 0, i32 %tidx
+  store float %scaled, float addrspace(3)* %sh
+  call void @llvm.nvvm.barrier0()
+  %back = load float, float addrspace(3)* %sh
+  %t = getelementptr [4 x i32], [4 x i32] addrspace(1)* @table, i32 0, i32 2
+  %tv = load i32, i32 addrspace(1)* %t
+  %tf = sitofp i32 %tv to float
+  %res = fadd float %back, %tf
+  %pc = getelementptr float, float* %c, i64 %i
+  store float %res, float* %pc
+  br label %exit
+exit:
+  ret void
+}
+
+!nvvm.annotations = !{!0}
+!0 = !{void (float*, float*, float*, i64)* @add, !"kernel", i32 1}
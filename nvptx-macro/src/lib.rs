@@ -0,0 +1,275 @@
+//! Embed PTX compiled from a kernel crate at compile time
+//!
+//! ```ignore
+//! mod add {
+//!     nvptx_macro::include_ptx!("../example");
+//! }
+//! // add::PTX: &str is the PTX of the kernel crate
+//! // add::kernels::add: &str is the name of the kernel `add`
+//! // add::demangled::add: &str is its demangled path, e.g. `add` or `kernel::add`
+//! ```
+//!
+//! The path is relative to `CARGO_MANIFEST_DIR` of the crate using this macro.
+//! Optional settings can be given after the path:
+//!
+//! ```ignore
+//! nvptx_macro::include_ptx!("../example", arch = "sm_60", toolchain = "accel-nvptx", release = true);
+//! ```
+//!
+//! The result is cached in `target/include_ptx.json` of the kernel crate, and the kernel crate
+//! is not rebuilt until its sources, the sources of its path dependencies, or settings change.
+
+extern crate proc_macro;
+
+use nvptx::build::{dependency_source_files, INCLUDE_PTX_CACHE};
+use nvptx::symbol;
+use nvptx::Driver;
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::*;
+use std::time::UNIX_EPOCH;
+use std::{env, fs};
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, Ident, LitBool, LitStr, Token};
+
+/// Settings given to `include_ptx!`
+struct Args {
+    path: LitStr,
+    arch: Option<String>,
+    toolchain: Option<String>,
+    release: bool,
+}
+
+impl Parse for Args {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = Args {
+            path: input.parse()?,
+            arch: None,
+            toolchain: None,
+            release: false,
+        };
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            match key.to_string().as_str() {
+                "arch" => args.arch = Some(input.parse::<LitStr>()?.value()),
+                "toolchain" => args.toolchain = Some(input.parse::<LitStr>()?.value()),
+                "release" => args.release = input.parse::<LitBool>()?.value,
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        "Unknown setting, expected one of arch, toolchain, release",
+                    ))
+                }
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Compiled PTX saved in the kernel crate
+#[derive(Serialize, Deserialize)]
+struct Cache {
+    /// Fingerprint of the sources and settings
+    key: String,
+    ptx_path: PathBuf,
+    kernels: Vec<String>,
+}
+
+/// Compile the kernel crate, and embed its PTX as `PTX`, kernel names in `kernels` module,
+/// and their demangled paths in `demangled` module
+#[proc_macro]
+pub fn include_ptx(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let args = parse_macro_input!(input as Args);
+    match expand(&args) {
+        Ok(tokens) => tokens.into(),
+        Err(msg) => syn::Error::new(args.path.span(), msg)
+            .to_compile_error()
+            .into(),
+    }
+}
+
+fn expand(args: &Args) -> Result<TokenStream, String> {
+    let path = match env::var_os("CARGO_MANIFEST_DIR") {
+        Some(dir) => PathBuf::from(dir).join(args.path.value()),
+        None => PathBuf::from(args.path.value()),
+    };
+    let path = fs::canonicalize(&path)
+        .map_err(|e| format!("Kernel crate {} is not found: {}", path.display(), e))?;
+    let sources =
+        dependency_source_files(&path).map_err(|e| format!("Cannot read kernel crate: {}", e))?;
+    let key = cache_key(args, &sources)?;
+
+    let cache_path = path.join(INCLUDE_PTX_CACHE);
+    let cache = match load_cache(&cache_path, &key) {
+        Some(cache) => cache,
+        None => {
+            let mut driver = Driver::with_path(&path).map_err(|e| e.to_string())?;
+            if let Some(arch) = &args.arch {
+                driver.set_arch(arch);
+            }
+            if let Some(toolchain) = &args.toolchain {
+                driver.set_toolchain(toolchain);
+            }
+            if args.release {
+                driver.release_build();
+            }
+            let output = driver.compile().map_err(|e| e.to_string())?;
+            let cache = Cache {
+                key,
+                ptx_path: output.ptx_path,
+                kernels: output.kernels,
+            };
+            // Failure of saving cache only makes the next expansion slower
+            if let Ok(json) = serde_json::to_string_pretty(&cache) {
+                let _ = fs::write(&cache_path, json);
+            }
+            cache
+        }
+    };
+    let ptx = fs::read_to_string(&cache.ptx_path)
+        .map_err(|e| format!("Cannot read {}: {}", cache.ptx_path.display(), e))?;
+
+    let idents = kernel_idents(&cache.kernels)?;
+    let kernels = idents.iter().map(|(ident, name)| {
+        let demangled = symbol::demangle(name);
+        quote! {
+            #[doc = #demangled]
+            pub const #ident: &str = #name;
+        }
+    });
+    let demangled = idents.iter().map(|(ident, name)| {
+        let demangled = symbol::demangle(name);
+        quote! { pub const #ident: &str = #demangled; }
+    });
+    // Track the kernel sources to expand again if they are changed
+    let sources = sources
+        .iter()
+        .map(|path| {
+            path.to_str()
+                .ok_or_else(|| format!("Non UTF-8 path in kernel crate: {}", path.display()))
+        })
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .map(|path| quote! { const _: &[u8] = include_bytes!(#path); });
+    Ok(quote! {
+        pub const PTX: &str = #ptx;
+        #[allow(non_upper_case_globals)]
+        pub mod kernels {
+            #(#kernels)*
+        }
+        #[allow(non_upper_case_globals)]
+        pub mod demangled {
+            #(#demangled)*
+        }
+        #(#sources)*
+    })
+}
+
+fn cache_key(args: &Args, sources: &[PathBuf]) -> Result<String, String> {
+    let mut key = format!(
+        "arch={:?};toolchain={:?};release={};",
+        args.arch, args.toolchain, args.release
+    );
+    for path in sources {
+        let meta = fs::metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        key.push_str(&format!(
+            "{}:{}:{}.{};",
+            path.display(),
+            meta.len(),
+            mtime.as_secs(),
+            mtime.subsec_nanos()
+        ));
+    }
+    Ok(key)
+}
+
+fn load_cache(path: &Path, key: &str) -> Option<Cache> {
+    let cache: Cache = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    if cache.key == key && cache.ptx_path.exists() {
+        Some(cache)
+    } else {
+        None
+    }
+}
+
+/// Rust identifier for the kernel name, e.g. mangled names may contain `$` or `.`
+fn kernel_ident(name: &str) -> Ident {
+    let ident: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let ident = match ident.chars().next() {
+        Some(c) if c.is_ascii_digit() => format!("_{}", ident),
+        None => "_".into(),
+        _ => ident,
+    };
+    match ident.as_str() {
+        // Keywords which cannot be raw identifiers
+        "_" | "self" | "super" | "crate" | "Self" => {
+            Ident::new(&format!("{}_", ident), Span::call_site())
+        }
+        // Other keywords are rejected by `syn`, e.g. `match`
+        _ if syn::parse_str::<Ident>(&ident).is_err() => Ident::new_raw(&ident, Span::call_site()),
+        _ => Ident::new(&ident, Span::call_site()),
+    }
+}
+
+/// Identifiers for the kernel names, or an error if two kernels are mapped into the same one
+fn kernel_idents(names: &[String]) -> Result<Vec<(Ident, &str)>, String> {
+    let mut seen: BTreeMap<String, &str> = BTreeMap::new();
+    let mut idents = Vec::new();
+    for name in names {
+        let ident = kernel_ident(name);
+        if let Some(other) = seen.insert(ident.to_string(), name) {
+            return Err(format!(
+                "Kernels `{}` and `{}` are both exported as `kernels::{}`. Rename one of them by `[package.metadata.nvptx.export]`",
+                other, name, ident
+            ));
+        }
+        idents.push((ident, name.as_str()));
+    }
+    Ok(idents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ident() {
+        assert_eq!(kernel_ident("add"), "add");
+        assert_eq!(
+            kernel_ident("_ZN4core3ptr13drop_in_place17h0123$LT$u8$GT$E"),
+            "_ZN4core3ptr13drop_in_place17h0123_LT_u8_GT_E"
+        );
+        assert_eq!(kernel_ident("0add"), "_0add");
+        assert_eq!(kernel_ident("match"), "r#match");
+        assert_eq!(kernel_ident("self"), "self_");
+
+        let names = vec!["a$b".to_string(), "a_b".to_string()];
+        assert!(kernel_idents(&names).is_err());
+        assert_eq!(kernel_idents(&names[..1]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn parse_args() {
+        let args: Args = syn::parse_str(r#""../example", arch = "sm_60", release = true"#).unwrap();
+        assert_eq!(args.path.value(), "../example");
+        assert_eq!(args.arch, Some("sm_60".into()));
+        assert_eq!(args.toolchain, None);
+        assert!(args.release);
+        assert!(syn::parse_str::<Args>(r#""../example", opt = "3""#).is_err());
+    }
+}
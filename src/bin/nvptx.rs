@@ -1,8 +1,18 @@
-use nvptx::error::{Logging, Step};
-use nvptx::{install, Driver};
+use colored::*;
+use nvptx::bitcode;
+use nvptx::callgraph::{self, KernelReport};
+use nvptx::error::{err_msg, Logging, Step};
+use nvptx::info::{shared_memory_limit, KernelInfo};
+use nvptx::ptx::Version;
+use nvptx::scaffold::Scaffold;
+use nvptx::symbol;
+use nvptx::sysroot::Sysroot;
+use nvptx::target::{Target, TargetSpec};
+use nvptx::timings::Timings;
+use nvptx::{default_jobs, install, CleanOptions, Driver};
 
-use std::env;
 use std::path::*;
+use std::{env, fs};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -29,6 +39,37 @@ enum Opt {
         /// alternative toolchain (default:sm_50)
         #[structopt(long = "arch")]
         arch: Option<String>,
+        /// sysroot built by `nvptx sysroot` (default:the one registered for the toolchain)
+        #[structopt(long = "sysroot", parse(from_os_str))]
+        sysroot: Option<PathBuf>,
+        /// target name or path of target specification JSON (default:nvptx64-nvidia-cuda)
+        #[structopt(long = "target")]
+        target: Option<String>,
+        /// Number of parallel jobs for converting rlibs (default:number of CPUs)
+        #[structopt(short = "j", long = "jobs")]
+        jobs: Option<usize>,
+        /// Show elapsed time of each stage
+        #[structopt(long = "timings")]
+        timings: bool,
+        /// Rewrite `.version` of generated PTX, e.g. `6.0`
+        #[structopt(long = "ptx-version")]
+        ptx_version: Option<Version>,
+        /// Rewrite `.target` of generated PTX, e.g. `sm_35`
+        #[structopt(long = "ptx-target")]
+        ptx_target: Option<String>,
+        /// Export a kernel as the name, e.g. `kernel::add=add`
+        #[structopt(
+            long = "export",
+            raw(number_of_values = "1"),
+            parse(try_from_str = "parse_export")
+        )]
+        export: Vec<(String, String)>,
+        /// Export only kernels matching the glob pattern, e.g. `add*`
+        #[structopt(long = "include-kernel", raw(number_of_values = "1"))]
+        include_kernel: Vec<String>,
+        /// Drop kernels matching the glob pattern, e.g. `accel_core::*`
+        #[structopt(long = "exclude-kernel", raw(number_of_values = "1"))]
+        exclude_kernel: Vec<String>,
     },
 
     /// Load PTX to stdout
@@ -38,6 +79,90 @@ enum Opt {
     )]
     Load {},
 
+    /// Show resource usage of each kernel in the generated PTX
+    #[structopt(
+        name = "info",
+        raw(setting = "structopt::clap::AppSettings::ColoredHelp")
+    )]
+    Info {
+        /// Output in JSON
+        #[structopt(long = "json")]
+        json: bool,
+        /// PTX of release build
+        #[structopt(long = "release")]
+        release: bool,
+        /// Architecture to check shared memory limit (default:sm_50)
+        #[structopt(long = "arch")]
+        arch: Option<String>,
+        /// target name or path of target specification JSON (default:nvptx64-nvidia-cuda)
+        #[structopt(long = "target")]
+        target: Option<String>,
+    },
+
+    /// Show functions and global variables in LLVM bitcode (or IR `*.ll`)
+    #[structopt(
+        name = "inspect",
+        raw(setting = "structopt::clap::AppSettings::ColoredHelp")
+    )]
+    Inspect {
+        /// Bitcode file, e.g. target/nvptx64-nvidia-cuda/debug/kernel.bc
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+
+    /// Show functions reachable from each kernel and their sizes in the linked bitcode
+    #[structopt(
+        name = "callgraph",
+        raw(setting = "structopt::clap::AppSettings::ColoredHelp")
+    )]
+    Callgraph {
+        /// Show only the kernel (symbol or demangled path)
+        #[structopt(long = "kernel")]
+        kernel: Option<String>,
+        /// Output format
+        #[structopt(
+            long = "format",
+            default_value = "text",
+            raw(possible_values = r#"&["text", "dot", "json"]"#)
+        )]
+        format: String,
+        /// Bitcode of release build
+        #[structopt(long = "release")]
+        release: bool,
+        /// target name or path of target specification JSON (default:nvptx64-nvidia-cuda)
+        #[structopt(long = "target")]
+        target: Option<String>,
+    },
+
+    /// Remove generated files (default:the output directory for nvptx target)
+    #[structopt(
+        name = "clean",
+        raw(setting = "structopt::clap::AppSettings::ColoredHelp")
+    )]
+    Clean {
+        /// Remove target/{target} directory
+        #[structopt(long = "target-dir")]
+        target_dir: bool,
+        /// Remove only linked bitcode, PTX and cubin
+        #[structopt(long = "artifacts")]
+        artifacts: bool,
+        /// Remove runtime bitcodes converted in the sysroot
+        #[structopt(long = "sysroot")]
+        sysroot: bool,
+        /// sysroot path (default:sysroot built by `nvptx sysroot`)
+        #[structopt(long = "sysroot-path", parse(from_os_str))]
+        sysroot_path: Option<PathBuf>,
+        /// Remove build state, bitcodes converted from rlibs, and cache of include_ptx!
+        #[structopt(long = "cache")]
+        cache: bool,
+        /// target name or path of target specification JSON (default:nvptx64-nvidia-cuda)
+        #[structopt(long = "target")]
+        target: Option<String>,
+        /// List files to be removed without removing them
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+    },
+
     /// Download and Install nvptx-enabled rustc
     #[structopt(
         name = "install",
@@ -47,6 +172,77 @@ enum Opt {
         /// Install path
         #[structopt(short = "p", long = "path", parse(from_os_str))]
         path: Option<PathBuf>,
+        /// Number of parallel jobs for converting rlibs (default:number of CPUs)
+        #[structopt(short = "j", long = "jobs")]
+        jobs: Option<usize>,
+    },
+
+    /// Build sysroot for nvptx target from rust-src of a nightly toolchain
+    #[structopt(
+        name = "sysroot",
+        raw(setting = "structopt::clap::AppSettings::ColoredHelp")
+    )]
+    Sysroot {
+        /// Nightly toolchain with rust-src component (default:nightly)
+        #[structopt(long = "toolchain")]
+        toolchain: Option<String>,
+        /// Build alloc crate in addition to core
+        #[structopt(long = "alloc")]
+        alloc: bool,
+        /// target name or path of target specification JSON (default:nvptx64-nvidia-cuda)
+        #[structopt(long = "target")]
+        target: Option<String>,
+        /// Install path of sysroot
+        #[structopt(short = "p", long = "path", parse(from_os_str))]
+        path: Option<PathBuf>,
+    },
+
+    /// Create a new kernel crate
+    #[structopt(
+        name = "new",
+        raw(setting = "structopt::clap::AppSettings::ColoredHelp")
+    )]
+    New {
+        /// Directory of the crate
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Package name (default:directory name)
+        #[structopt(long = "name")]
+        name: Option<String>,
+        /// Generate host crate compiling the kernel in build.rs
+        #[structopt(long = "host")]
+        host: bool,
+    },
+
+    /// Create a kernel crate in an existing directory
+    #[structopt(
+        name = "init",
+        raw(setting = "structopt::clap::AppSettings::ColoredHelp")
+    )]
+    Init {
+        /// Directory of the crate (default:current directory)
+        #[structopt(parse(from_os_str))]
+        path: Option<PathBuf>,
+        /// Package name (default:directory name)
+        #[structopt(long = "name")]
+        name: Option<String>,
+        /// Generate host crate compiling the kernel in build.rs
+        #[structopt(long = "host")]
+        host: bool,
+    },
+
+    /// Generate default target specification JSON
+    #[structopt(
+        name = "target-spec",
+        raw(setting = "structopt::clap::AppSettings::ColoredHelp")
+    )]
+    TargetSpec {
+        /// 32-bit nvptx-nvidia-cuda instead of nvptx64-nvidia-cuda
+        #[structopt(long = "nvptx32")]
+        nvptx32: bool,
+        /// Output file (default:stdout)
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
     },
 }
 
@@ -65,6 +261,159 @@ fn get_manifest_path() -> PathBuf {
     }
 }
 
+fn scaffold(path: &Path, name: Option<String>, host: bool) -> Scaffold {
+    let mut scaffold = Scaffold::new(path);
+    if let Some(name) = name {
+        scaffold.set_name(&name);
+    }
+    if host {
+        scaffold.with_host();
+    }
+    scaffold
+}
+
+/// Parse `<kernel>=<name>` of `--export`
+fn parse_export(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((kernel, name)) if !kernel.is_empty() && !name.is_empty() => {
+            Ok((kernel.into(), name.into()))
+        }
+        _ => Err(format!("Expected <kernel>=<name>: {}", arg)),
+    }
+}
+
+fn print_kernel_info(kernels: &[KernelInfo]) {
+    let dims = |d: &Option<Vec<u32>>| match d {
+        Some(d) => d
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+        None => "-".into(),
+    };
+    let width = kernels
+        .iter()
+        .map(|k| k.demangled.len())
+        .max()
+        .unwrap_or(0)
+        .max(6);
+    println!(
+        "{:<width$} {:>9} {:>9} {:>9} {:>9} {:>11} {:>11}",
+        "kernel",
+        "registers",
+        "shared",
+        "local",
+        "params",
+        "maxntid",
+        "reqntid",
+        width = width
+    );
+    for k in kernels {
+        let shared = if k.dynamic_shared {
+            format!("{}+dyn", k.shared)
+        } else {
+            k.shared.to_string()
+        };
+        println!(
+            "{:<width$} {:>9} {:>9} {:>9} {:>9} {:>11} {:>11}",
+            k.demangled,
+            k.total_registers(),
+            shared,
+            k.local,
+            k.params,
+            dims(&k.maxntid),
+            dims(&k.reqntid),
+            width = width
+        );
+    }
+}
+
+fn print_module(module: &bitcode::Module) {
+    println!("target triple: {}", module.target_triple());
+    println!("data layout:   {}", module.data_layout());
+    let functions = module.functions();
+    println!("\nfunctions ({}):", functions.len());
+    for f in &functions {
+        let attrs = f.attributes();
+        println!(
+            "  {:<7} {:<10} {:<12} {:<9} {}{}",
+            if f.is_declaration() {
+                "declare"
+            } else {
+                "define"
+            },
+            f.calling_convention(),
+            f.linkage(),
+            f.visibility(),
+            symbol::demangle(&f.name()),
+            if attrs.is_empty() {
+                String::new()
+            } else {
+                format!("  [{}]", attrs.join(", "))
+            }
+        );
+    }
+    let globals = module.globals();
+    println!("\nglobals ({}):", globals.len());
+    for g in &globals {
+        println!(
+            "  {:<7} {:<10} {:<12} {:<9} {}{}",
+            if g.is_declaration() {
+                "declare"
+            } else {
+                "define"
+            },
+            bitcode::address_space_name(g.address_space()),
+            g.linkage(),
+            g.visibility(),
+            symbol::demangle(&g.name()),
+            if g.is_constant() { "  [constant]" } else { "" }
+        );
+    }
+}
+
+fn print_call_graph(reports: &[KernelReport]) {
+    for r in reports {
+        println!(
+            "kernel {} ({} instructions, {} functions)",
+            r.demangled,
+            r.instructions,
+            r.functions.len()
+        );
+        for f in &r.functions {
+            println!(
+                "  {:>8}  {}{}",
+                f.instructions,
+                f.demangled,
+                if f.declaration {
+                    "  [declare]".to_string()
+                } else if f.indirect_calls > 0 {
+                    format!("  [{} indirect calls]", f.indirect_calls)
+                } else {
+                    String::new()
+                }
+            );
+        }
+    }
+}
+
+fn print_timings(timings: &Timings) {
+    for (stage, t) in timings.stages() {
+        eprintln!(
+            "{:>12} {:<12} {:>8.3}s",
+            "Timing".bright_green(),
+            stage,
+            t.as_secs_f64()
+        );
+    }
+    eprintln!(
+        "{:>12} {:<12} {:>8.3}s",
+        "Timing".bright_green(),
+        "total",
+        timings.total().as_secs_f64()
+    );
+}
+
 fn main() -> nvptx::error::Result<()> {
     let opt = Opt::from_args();
 
@@ -75,6 +424,15 @@ fn main() -> nvptx::error::Result<()> {
             release,
             toolchain,
             arch,
+            sysroot,
+            target,
+            jobs,
+            timings,
+            ptx_version,
+            ptx_target,
+            export,
+            include_kernel,
+            exclude_kernel,
         } => {
             let manifest_path = get_manifest_path();
             let mut driver = Driver::with_path(manifest_path)?;
@@ -84,25 +442,208 @@ fn main() -> nvptx::error::Result<()> {
             if let Some(arch) = arch {
                 driver.set_arch(&arch);
             }
+            if let Some(sysroot) = sysroot {
+                driver.set_sysroot(&sysroot);
+            }
+            if let Some(target) = target {
+                driver.set_target(&target);
+            }
+            if let Some(jobs) = jobs {
+                driver.set_jobs(jobs);
+            }
+            if let Some(version) = ptx_version {
+                driver.set_ptx_version(version);
+            }
+            if let Some(ptx_target) = ptx_target {
+                driver.set_ptx_target(&ptx_target);
+            }
+            for (kernel, name) in export {
+                driver.set_export_name(&kernel, &name);
+            }
+            let include: Vec<&str> = include_kernel.iter().map(|p| p.as_str()).collect();
+            let exclude: Vec<&str> = exclude_kernel.iter().map(|p| p.as_str()).collect();
+            driver.set_kernel_patterns(&include, &exclude);
             if release {
                 driver.release_build();
             }
-            driver.compile()?;
+            let output = driver.compile()?;
             if load {
-                println!("{}", driver.load_ptx()?);
+                println!("{}", output.ptx);
             }
             if cubin {
                 driver.cubin()?;
             }
+            if timings {
+                print_timings(&driver.timings());
+            }
         }
         Opt::Load {} => {
             let manifest_path = get_manifest_path();
             let driver = Driver::with_path(manifest_path)?;
             println!("{}", driver.load_ptx()?);
         }
-        Opt::Install { path } => {
-            install(&path.unwrap_or(dirs::data_dir().unwrap().join("accel-nvptx")))
-                .log_unwrap(Step::Install)?;
+        Opt::Info {
+            json,
+            release,
+            arch,
+            target,
+        } => {
+            let manifest_path = get_manifest_path();
+            let mut driver = Driver::with_path(manifest_path)?;
+            if let Some(arch) = arch {
+                driver.set_arch(&arch);
+            }
+            if let Some(target) = target {
+                driver.set_target(&target);
+            }
+            if release {
+                driver.release_build();
+            }
+            let kernels = driver.kernel_info()?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&kernels).unwrap());
+            } else {
+                print_kernel_info(&kernels);
+            }
+            for k in kernels
+                .iter()
+                .filter(|k| k.exceeds_shared_limit(driver.arch()))
+            {
+                eprintln!(
+                    "{}: kernel `{}` uses {} bytes of static shared memory, exceeding {} bytes on {}",
+                    "warning".bright_yellow(),
+                    k.demangled,
+                    k.shared,
+                    shared_memory_limit(driver.arch()),
+                    driver.arch()
+                );
+            }
+        }
+        Opt::Callgraph {
+            kernel,
+            format,
+            release,
+            target,
+        } => {
+            let manifest_path = get_manifest_path();
+            let mut driver = Driver::with_path(manifest_path)?;
+            if let Some(target) = target {
+                driver.set_target(&target);
+            }
+            if release {
+                driver.release_build();
+            }
+            let graph = driver.call_graph()?;
+            let kernels: Vec<&callgraph::Node> = match kernel {
+                Some(name) => match graph.find_kernel(&name) {
+                    Some(k) => vec![k],
+                    None => {
+                        let comment = format!("Kernel not found: {}", name);
+                        return Err(err_msg(Step::Load, &comment));
+                    }
+                },
+                None => graph
+                    .kernels()
+                    .iter()
+                    .map(|k| &graph.functions[*k])
+                    .collect(),
+            };
+            let reports: Vec<_> = kernels.iter().map(|k| graph.report(k)).collect();
+            match format.as_str() {
+                "dot" => {
+                    let roots: Vec<&str> = kernels.iter().map(|k| k.name.as_str()).collect();
+                    print!("{}", graph.to_dot(&roots));
+                }
+                "json" => println!("{}", serde_json::to_string_pretty(&reports).unwrap()),
+                _ => print_call_graph(&reports),
+            }
+        }
+        Opt::Inspect { file } => {
+            let module =
+                bitcode::Module::read(&file).log(Step::Load, "Fail to read LLVM bitcode")?;
+            print_module(&module);
+        }
+        Opt::Clean {
+            target_dir,
+            artifacts,
+            sysroot,
+            sysroot_path,
+            cache,
+            target,
+            dry_run,
+        } => {
+            let manifest_path = get_manifest_path();
+            let mut driver = Driver::with_path(manifest_path)?;
+            if let Some(sysroot) = sysroot_path {
+                driver.set_sysroot(&sysroot);
+            }
+            if let Some(target) = target {
+                driver.set_target(&target);
+            }
+            let opts = CleanOptions {
+                target_dir: target_dir || !(artifacts || sysroot || cache),
+                artifacts,
+                sysroot,
+                cache,
+                dry_run,
+            };
+            let removed = driver.clean(&opts)?;
+            let verb = if dry_run { "Would remove" } else { "Removed" };
+            for path in &removed {
+                eprintln!("{:>12} {}", verb.bright_green(), path.display());
+            }
+            if removed.is_empty() {
+                eprintln!("{:>12} nothing to remove", "Clean".bright_green());
+            }
+        }
+        Opt::Install { path, jobs } => {
+            let jobs = jobs.unwrap_or_else(default_jobs);
+            install(
+                &path.unwrap_or(dirs::data_dir().unwrap().join("accel-nvptx")),
+                jobs,
+            )
+            .log_unwrap(Step::Install)?;
+        }
+        Opt::Sysroot {
+            toolchain,
+            alloc,
+            target,
+            path,
+        } => {
+            let mut sysroot = Sysroot::new(&toolchain.unwrap_or("nightly".into()));
+            if let Some(path) = path {
+                sysroot.set_path(&path);
+            }
+            if alloc {
+                sysroot.with_alloc();
+            }
+            if let Some(target) = target {
+                sysroot.set_target(Target::new(&target));
+            }
+            sysroot.build().log_unwrap(Step::Install)?;
+        }
+        Opt::New { path, name, host } => {
+            scaffold(&path, name, host)
+                .create()
+                .log(Step::Ready, "Failed to create kernel crate")?;
+        }
+        Opt::Init { path, name, host } => {
+            let path = path.unwrap_or_else(|| env::current_dir().unwrap());
+            scaffold(&path, name, host)
+                .init()
+                .log(Step::Ready, "Failed to create kernel crate")?;
+        }
+        Opt::TargetSpec { nvptx32, output } => {
+            let spec = if nvptx32 {
+                TargetSpec::nvptx()
+            } else {
+                TargetSpec::nvptx64()
+            };
+            match output {
+                Some(output) => fs::write(&output, spec.as_json())
+                    .log(Step::Ready, "Failed to write target specification")?,
+                None => println!("{}", spec.as_json()),
+            }
         }
     }
     Ok(())
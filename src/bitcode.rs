@@ -1,14 +1,81 @@
+//! Inspect LLVM bitcode
+//!
+//! ```no_run
+//! use nvptx::bitcode::Module;
+//!
+//! let module = Module::read_bitcode("target/nvptx64-nvidia-cuda/release/kernel.bc").unwrap();
+//! println!("{}", module.target_triple());
+//! for f in module.functions() {
+//!     println!("{} {} {}", f.calling_convention(), f.linkage(), f.name());
+//! }
+//! ```
+
 use llvm_sys::bit_reader::*;
+use llvm_sys::bit_writer::*;
 use llvm_sys::core::*;
+use llvm_sys::ir_reader::LLVMParseIRInContext;
 use llvm_sys::prelude::*;
+use llvm_sys::{LLVMAttributeFunctionIndex, LLVMLinkage, LLVMVisibility};
 
 use failure::err_msg;
+use std::collections::BTreeMap;
 use std::ffi::*;
+use std::marker::PhantomData;
 use std::os::raw::c_char;
 use std::path::*;
 use std::ptr::null_mut;
 
 use crate::error::*;
+use crate::manifest::LaunchBounds;
+use crate::symbol;
+
+// `LLVMOpcode` and `LLVMTypeKind` of llvm-sys lack the values added in later LLVM (e.g. `fneg`),
+// and these functions are declared to return the raw values instead.
+extern "C" {
+    #[link_name = "LLVMGetInstructionOpcode"]
+    fn get_instruction_opcode(inst: LLVMValueRef) -> u32;
+    #[link_name = "LLVMGetConstOpcode"]
+    fn get_const_opcode(value: LLVMValueRef) -> u32;
+    #[link_name = "LLVMGetTypeKind"]
+    fn get_type_kind(ty: LLVMTypeRef) -> u32;
+}
+
+/// `LLVMIntegerTypeKind`
+const INTEGER_TYPE_KIND: u32 = 8;
+
+/// Enum attributes shown by [Function::attributes]
+const ATTRIBUTES: [&str; 30] = [
+    "alwaysinline",
+    "argmemonly",
+    "builtin",
+    "cold",
+    "convergent",
+    "hot",
+    "inaccessiblememonly",
+    "inlinehint",
+    "minsize",
+    "mustprogress",
+    "naked",
+    "nobuiltin",
+    "noduplicate",
+    "nofree",
+    "noinline",
+    "nomerge",
+    "norecurse",
+    "noreturn",
+    "nosync",
+    "nounwind",
+    "optnone",
+    "optsize",
+    "readnone",
+    "readonly",
+    "returns_twice",
+    "speculatable",
+    "ssp",
+    "uwtable",
+    "willreturn",
+    "writeonly",
+];
 
 struct MemoryBuffer(LLVMMemoryBufferRef);
 
@@ -25,24 +92,90 @@ impl MemoryBuffer {
         let mut msg: *mut c_char = null_mut();
         let result = unsafe {
             LLVMCreateMemoryBufferWithContentsOfFile(
-                input.into_raw(),
+                input.as_ptr(),
                 &mut membuf as *mut LLVMMemoryBufferRef,
                 &mut msg as *mut *mut c_char,
             )
         };
         if result != 0 {
-            let msg = unsafe { CString::from_raw(msg) };
-            return Err(err_msg(format!("Canont read input: {:?}", msg)));
+            let text = unsafe { CStr::from_ptr(msg) }
+                .to_string_lossy()
+                .into_owned();
+            unsafe { LLVMDisposeMessage(msg) };
+            return Err(err_msg(format!("Cannot read input: {}", text)));
         }
         Ok(MemoryBuffer(membuf))
     }
 }
 
+/// LLVM module read from bitcode or IR
 #[derive(Debug)]
-struct Module(LLVMModuleRef);
+pub struct Module(LLVMModuleRef);
 
-#[derive(Debug)]
-struct Function(LLVMValueRef);
+/// Function in a [Module]
+#[derive(Debug, Clone, Copy)]
+pub struct Function<'m> {
+    value: LLVMValueRef,
+    module: PhantomData<&'m Module>,
+}
+
+/// Instruction in a [Function]
+#[derive(Debug, Clone, Copy)]
+pub struct Instruction<'m> {
+    value: LLVMValueRef,
+    module: PhantomData<&'m Module>,
+}
+
+/// Opcode of [Instruction], the values are those of `LLVMOpcode` in LLVM C API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Invoke,
+    Mul,
+    UDiv,
+    SDiv,
+    URem,
+    SRem,
+    Shl,
+    LShr,
+    AShr,
+    BitCast,
+    Call,
+    CallBr,
+    Other(u32),
+}
+
+impl Opcode {
+    fn from_raw(op: u32) -> Self {
+        match op {
+            5 => Opcode::Invoke,
+            12 => Opcode::Mul,
+            14 => Opcode::UDiv,
+            15 => Opcode::SDiv,
+            17 => Opcode::URem,
+            18 => Opcode::SRem,
+            20 => Opcode::Shl,
+            21 => Opcode::LShr,
+            22 => Opcode::AShr,
+            41 => Opcode::BitCast,
+            45 => Opcode::Call,
+            67 => Opcode::CallBr,
+            op => Opcode::Other(op),
+        }
+    }
+}
+
+/// Global variable in a [Module]
+#[derive(Debug, Clone, Copy)]
+pub struct GlobalVariable<'m> {
+    value: LLVMValueRef,
+    module: PhantomData<&'m Module>,
+}
+
+impl Drop for Module {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeModule(self.0) }
+    }
+}
 
 impl Module {
     fn parse_bitcode(buf: &MemoryBuffer) -> ResultAny<Self> {
@@ -54,26 +187,201 @@ impl Module {
         Ok(Module(md))
     }
 
-    fn read_bitcode(filename: &str) -> ResultAny<Self> {
-        let membuf = MemoryBuffer::new(filename)?;
+    pub fn read_bitcode<P: AsRef<Path>>(filename: P) -> ResultAny<Self> {
+        let path = filename.as_ref();
+        let path = path
+            .to_str()
+            .ok_or_else(|| err_msg(format!("Invalid path: {}", path.display())))?;
+        let membuf = MemoryBuffer::new(path)?;
         Self::parse_bitcode(&membuf)
     }
 
-    fn functions(&self) -> Vec<Function> {
+    /// Parse LLVM IR in text form
+    pub fn parse_ir(ir: &str) -> ResultAny<Self> {
+        let mut md: LLVMModuleRef = null_mut();
+        let mut msg: *mut c_char = null_mut();
+        let res = unsafe {
+            // The buffer is owned by the parsed module
+            let buf = LLVMCreateMemoryBufferWithMemoryRangeCopy(
+                ir.as_ptr() as *const c_char,
+                ir.len(),
+                b"ir\0".as_ptr() as *const c_char,
+            );
+            LLVMParseIRInContext(LLVMGetGlobalContext(), buf, &mut md, &mut msg)
+        };
+        if res != 0 {
+            let text = unsafe { CStr::from_ptr(msg) }
+                .to_string_lossy()
+                .into_owned();
+            unsafe { LLVMDisposeMessage(msg) };
+            return Err(err_msg(format!("Cannot parse LLVM IR: {}", text)));
+        }
+        Ok(Module(md))
+    }
+
+    /// Read LLVM IR (`*.ll`) or bitcode (others)
+    pub fn read<P: AsRef<Path>>(filename: P) -> ResultAny<Self> {
+        let path = filename.as_ref();
+        if path.extension().is_some_and(|ext| ext == "ll") {
+            Self::parse_ir(&std::fs::read_to_string(path)?)
+        } else {
+            Self::read_bitcode(path)
+        }
+    }
+
+    pub fn write_bitcode<P: AsRef<Path>>(&self, filename: P) -> ResultAny<()> {
+        let filename = filename.as_ref();
+        let path = CString::new(filename.to_str().unwrap())?;
+        if unsafe { LLVMWriteBitcodeToFile(self.0, path.as_ptr()) } != 0 {
+            return Err(err_msg(format!(
+                "Cannot write LLVM Bitcode: {}",
+                filename.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Target triple, e.g. `nvptx64-nvidia-cuda`
+    pub fn target_triple(&self) -> String {
+        unsafe { to_string(LLVMGetTarget(self.0)) }
+    }
+
+    pub fn data_layout(&self) -> String {
+        unsafe { to_string(LLVMGetDataLayoutStr(self.0)) }
+    }
+
+    pub fn functions(&self) -> Vec<Function<'_>> {
         let mut funcs = Vec::new();
         let mut f = unsafe { LLVMGetFirstFunction(self.0) };
-        while f != null_mut() {
-            funcs.push(Function(f));
+        while !f.is_null() {
+            funcs.push(Function::new(f));
             f = unsafe { LLVMGetNextFunction(f) };
         }
         funcs
     }
+
+    pub fn function(&self, name: &str) -> Option<Function<'_>> {
+        let name = CString::new(name).ok()?;
+        let f = unsafe { LLVMGetNamedFunction(self.0, name.as_ptr()) };
+        if f.is_null() {
+            None
+        } else {
+            Some(Function::new(f))
+        }
+    }
+
+    pub fn globals(&self) -> Vec<GlobalVariable<'_>> {
+        let mut globals = Vec::new();
+        let mut g = unsafe { LLVMGetFirstGlobal(self.0) };
+        while !g.is_null() {
+            globals.push(GlobalVariable {
+                value: g,
+                module: PhantomData,
+            });
+            g = unsafe { LLVMGetNextGlobal(g) };
+        }
+        globals
+    }
+
+    /// Functions and global variables declared without definition, except LLVM intrinsics.
+    /// Only variables in the generic or global address space are counted, since extern
+    /// `shared` (e.g. dynamic shared memory) and `const` variables are resolved at launch.
+    pub fn undefined_symbols(&self) -> Vec<String> {
+        let functions = self
+            .functions()
+            .into_iter()
+            .filter(|f| f.is_declaration() && !f.is_intrinsic())
+            .map(|f| f.name());
+        let globals = self
+            .globals()
+            .into_iter()
+            .filter(|g| g.is_declaration() && g.address_space() <= 1)
+            .map(|g| g.name());
+        functions.chain(globals).collect()
+    }
+
+    /// Append `!{f, !"key", i32 value}` to `!nvvm.annotations`
+    fn annotate(&self, f: &Function, key: &str, value: u32) {
+        unsafe {
+            let ctx = LLVMGetModuleContext(self.0);
+            let mut operands = [
+                f.value,
+                LLVMMDStringInContext(ctx, key.as_ptr() as *const c_char, key.len() as u32),
+                LLVMConstInt(LLVMInt32TypeInContext(ctx), u64::from(value), 0),
+            ];
+            let node = LLVMMDNodeInContext(ctx, operands.as_mut_ptr(), operands.len() as u32);
+            LLVMAddNamedMetadataOperand(
+                self.0,
+                b"nvvm.annotations\0".as_ptr() as *const c_char,
+                node,
+            );
+        }
+    }
+    /// Rename PTX functions named by the symbol or its demangled path,
+    /// and returns the renamed symbols
+    fn rename(&self, names: &BTreeMap<String, String>) -> ResultAny<Vec<(String, String)>> {
+        let mut renamed = Vec::new();
+        for (name, export) in names {
+            let mut targets: Vec<Function> = self
+                .functions()
+                .into_iter()
+                .filter(|f| f.is_ptx_kernel() || f.is_ptx_device_func())
+                .filter(|f| symbol::matches(&f.name(), name))
+                .collect();
+            let f = match targets.len() {
+                0 => return Err(err_msg(format!("Kernel `{}` is not found", name))),
+                1 => targets.pop().unwrap(),
+                _ => return Err(err_msg(format!("Kernel `{}` is ambiguous", name))),
+            };
+            let old = f.name();
+            if old == *export {
+                continue;
+            }
+            if self.function(export).is_some() {
+                return Err(err_msg(format!(
+                    "Cannot export `{}` as `{}`, which is already defined",
+                    name, export
+                )));
+            }
+            let new = CString::new(export.as_str())?;
+            unsafe { LLVMSetValueName(f.value, new.as_ptr()) };
+            renamed.push((old, export.clone()));
+        }
+        Ok(renamed)
+    }
+
+    /// Annotate kernels named by the symbol or its demangled path
+    fn set_launch_bounds(&self, bounds: &BTreeMap<String, LaunchBounds>) -> ResultAny<()> {
+        for (name, bounds) in bounds {
+            let targets: Vec<Function> = self
+                .functions()
+                .into_iter()
+                .filter(|f| f.is_ptx_kernel() && symbol::matches(&f.name(), name))
+                .collect();
+            if targets.is_empty() {
+                return Err(err_msg(format!("Kernel `{}` is not found", name)));
+            }
+            let annotations = bounds.annotations()?;
+            for f in &targets {
+                for (key, value) in &annotations {
+                    self.annotate(f, key, *value);
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
-impl Function {
-    fn name(&self) -> String {
-        let name = unsafe { CString::from_raw(LLVMGetValueName(self.0) as *mut _) };
-        name.into_string().expect("Fail to parse function name")
+impl<'m> Function<'m> {
+    fn new(value: LLVMValueRef) -> Self {
+        Function {
+            value,
+            module: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        value_name(self.value)
     }
 
     // See the LLVM call convention list
@@ -82,30 +390,513 @@ impl Function {
     // - PTX_Device = 72
     //
     // http://llvm.org/doxygen/CallingConv_8h_source.html
-    fn call_conv(&self) -> u32 {
-        unsafe { LLVMGetFunctionCallConv(self.0) }
+    pub fn call_conv(&self) -> u32 {
+        unsafe { LLVMGetFunctionCallConv(self.value) }
     }
 
-    fn is_ptx_kernel(&self) -> bool {
+    /// Calling convention in LLVM IR, e.g. `ptx_kernel`
+    pub fn calling_convention(&self) -> String {
+        match self.call_conv() {
+            0 => "ccc".into(),
+            71 => "ptx_kernel".into(),
+            72 => "ptx_device".into(),
+            cc => format!("cc {}", cc),
+        }
+    }
+
+    pub fn is_ptx_kernel(&self) -> bool {
         self.call_conv() == 71
     }
 
-    fn is_ptx_device_func(&self) -> bool {
+    pub fn is_ptx_device_func(&self) -> bool {
         self.call_conv() == 72
     }
+
+    /// Linkage in LLVM IR, e.g. `internal`
+    pub fn linkage(&self) -> &'static str {
+        linkage(self.value)
+    }
+
+    /// Visibility in LLVM IR, e.g. `hidden`
+    pub fn visibility(&self) -> &'static str {
+        visibility(self.value)
+    }
+
+    /// Declared without body, which must be resolved by linking
+    pub fn is_declaration(&self) -> bool {
+        unsafe { LLVMIsDeclaration(self.value) != 0 }
+    }
+
+    /// LLVM intrinsic, e.g. `llvm.nvvm.read.ptx.sreg.tid.x`
+    pub fn is_intrinsic(&self) -> bool {
+        self.name().starts_with("llvm.")
+    }
+
+    /// Instructions in all basic blocks, empty for a declaration
+    pub fn instructions(&self) -> Vec<Instruction<'m>> {
+        let mut insts = Vec::new();
+        unsafe {
+            let mut bb = LLVMGetFirstBasicBlock(self.value);
+            while !bb.is_null() {
+                let mut inst = LLVMGetFirstInstruction(bb);
+                while !inst.is_null() {
+                    insts.push(Instruction {
+                        value: inst,
+                        module: PhantomData,
+                    });
+                    inst = LLVMGetNextInstruction(inst);
+                }
+                bb = LLVMGetNextBasicBlock(bb);
+            }
+        }
+        insts
+    }
+
+    /// Functions called directly from this function
+    pub fn callees(&self) -> Vec<Function<'m>> {
+        let mut callees: Vec<Function<'m>> = Vec::new();
+        for f in self
+            .instructions()
+            .iter()
+            .filter_map(|i| i.called_function())
+        {
+            if !callees.iter().any(|g| g.value == f.value) {
+                callees.push(f);
+            }
+        }
+        callees
+    }
+
+    /// Function attributes, e.g. `["nounwind", "\"target-cpu\"=\"sm_50\""]`
+    pub fn attributes(&self) -> Vec<String> {
+        let attrs = unsafe {
+            let n = LLVMGetAttributeCountAtIndex(self.value, LLVMAttributeFunctionIndex);
+            let mut attrs = vec![null_mut(); n as usize];
+            LLVMGetAttributesAtIndex(self.value, LLVMAttributeFunctionIndex, attrs.as_mut_ptr());
+            attrs
+        };
+        attrs
+            .into_iter()
+            .map(|attr| unsafe {
+                if LLVMIsStringAttribute(attr) != 0 {
+                    let mut len = 0;
+                    let key = LLVMGetStringAttributeKind(attr, &mut len);
+                    let key = String::from_utf8_lossy(std::slice::from_raw_parts(
+                        key as *const u8,
+                        len as usize,
+                    ));
+                    let value = LLVMGetStringAttributeValue(attr, &mut len);
+                    let value = String::from_utf8_lossy(std::slice::from_raw_parts(
+                        value as *const u8,
+                        len as usize,
+                    ));
+                    format!("\"{}\"=\"{}\"", key, value)
+                } else {
+                    let kind = LLVMGetEnumAttributeKind(attr);
+                    let name = ATTRIBUTES
+                        .iter()
+                        .find(|name| {
+                            LLVMGetEnumAttributeKindForName(
+                                name.as_ptr() as *const c_char,
+                                name.len(),
+                            ) == kind
+                        })
+                        .map(|name| name.to_string())
+                        .unwrap_or_else(|| format!("#{}", kind));
+                    match LLVMGetEnumAttributeValue(attr) {
+                        0 => name,
+                        value => format!("{}({})", name, value),
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+impl<'m> Instruction<'m> {
+    pub fn opcode(&self) -> Opcode {
+        Opcode::from_raw(unsafe { get_instruction_opcode(self.value) })
+    }
+
+    pub fn is_call(&self) -> bool {
+        matches!(
+            self.opcode(),
+            Opcode::Call | Opcode::Invoke | Opcode::CallBr
+        )
+    }
+
+    /// Function called directly, `None` for indirect calls, inline assembly and other instructions
+    pub fn called_function(&self) -> Option<Function<'m>> {
+        if !self.is_call() {
+            return None;
+        }
+        let callee = strip_bitcast(unsafe { LLVMGetCalledValue(self.value) });
+        if unsafe { LLVMIsAFunction(callee) }.is_null() {
+            None
+        } else {
+            Some(Function::new(callee))
+        }
+    }
+
+    /// Call through a function pointer
+    pub fn is_indirect_call(&self) -> bool {
+        self.is_call()
+            && self.called_function().is_none()
+            && unsafe { LLVMIsAInlineAsm(LLVMGetCalledValue(self.value)) }.is_null()
+    }
+
+    /// Bit width of the result if it is an integer, e.g. `Some(128)` for `i128`
+    pub fn int_width(&self) -> Option<u32> {
+        unsafe {
+            let ty = LLVMTypeOf(self.value);
+            if get_type_kind(ty) == INTEGER_TYPE_KIND {
+                Some(LLVMGetIntTypeWidth(ty))
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Global variables used as operands, including those in constant expressions
+    pub fn globals(&self) -> Vec<GlobalVariable<'m>> {
+        let mut globals = Vec::new();
+        collect_globals(self.value, &mut globals);
+        globals
+    }
+}
+
+fn strip_bitcast(mut value: LLVMValueRef) -> LLVMValueRef {
+    unsafe {
+        while !LLVMIsAConstantExpr(value).is_null()
+            && Opcode::from_raw(get_const_opcode(value)) == Opcode::BitCast
+        {
+            value = LLVMGetOperand(value, 0);
+        }
+    }
+    value
+}
+
+fn collect_globals<'m>(value: LLVMValueRef, globals: &mut Vec<GlobalVariable<'m>>) {
+    unsafe {
+        for i in 0..LLVMGetNumOperands(value).max(0) {
+            let op = LLVMGetOperand(value, i as u32);
+            if op.is_null() {
+                continue;
+            }
+            if !LLVMIsAGlobalVariable(op).is_null() {
+                if !globals.iter().any(|g| g.value == op) {
+                    globals.push(GlobalVariable {
+                        value: op,
+                        module: PhantomData,
+                    });
+                }
+            } else if !LLVMIsAConstantExpr(op).is_null() {
+                collect_globals(op, globals);
+            }
+        }
+    }
+}
+
+impl<'m> GlobalVariable<'m> {
+    pub fn name(&self) -> String {
+        value_name(self.value)
+    }
+
+    /// Address space number, e.g. `3` for shared memory
+    pub fn address_space(&self) -> u32 {
+        unsafe { LLVMGetPointerAddressSpace(LLVMTypeOf(self.value)) }
+    }
+
+    pub fn linkage(&self) -> &'static str {
+        linkage(self.value)
+    }
+
+    pub fn visibility(&self) -> &'static str {
+        visibility(self.value)
+    }
+
+    pub fn is_declaration(&self) -> bool {
+        unsafe { LLVMIsDeclaration(self.value) != 0 }
+    }
+
+    pub fn is_constant(&self) -> bool {
+        unsafe { LLVMIsGlobalConstant(self.value) != 0 }
+    }
+}
+
+/// Name of NVPTX address space, e.g. `shared` for 3
+pub fn address_space_name(space: u32) -> &'static str {
+    match space {
+        0 => "generic",
+        1 => "global",
+        3 => "shared",
+        4 => "const",
+        5 => "local",
+        _ => "unknown",
+    }
+}
+
+unsafe fn to_string(s: *const c_char) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(s).to_string_lossy().into_owned()
+}
+
+fn value_name(value: LLVMValueRef) -> String {
+    // The name is owned by LLVM, and must not be freed here
+    let name = unsafe { CStr::from_ptr(LLVMGetValueName(value)) };
+    name.to_str().expect("Fail to parse function name").into()
+}
+
+fn linkage(value: LLVMValueRef) -> &'static str {
+    use LLVMLinkage::*;
+    match unsafe { LLVMGetLinkage(value) } {
+        LLVMExternalLinkage => "external",
+        LLVMAvailableExternallyLinkage => "available_externally",
+        LLVMLinkOnceAnyLinkage => "linkonce",
+        LLVMLinkOnceODRLinkage | LLVMLinkOnceODRAutoHideLinkage => "linkonce_odr",
+        LLVMWeakAnyLinkage => "weak",
+        LLVMWeakODRLinkage => "weak_odr",
+        LLVMAppendingLinkage => "appending",
+        LLVMInternalLinkage => "internal",
+        LLVMPrivateLinkage | LLVMLinkerPrivateLinkage | LLVMLinkerPrivateWeakLinkage => "private",
+        LLVMDLLImportLinkage | LLVMDLLExportLinkage | LLVMGhostLinkage => "external",
+        LLVMExternalWeakLinkage => "extern_weak",
+        LLVMCommonLinkage => "common",
+    }
+}
+
+fn visibility(value: LLVMValueRef) -> &'static str {
+    match unsafe { LLVMGetVisibility(value) } {
+        LLVMVisibility::LLVMDefaultVisibility => "default",
+        LLVMVisibility::LLVMHiddenVisibility => "hidden",
+        LLVMVisibility::LLVMProtectedVisibility => "protected",
+    }
 }
 
 pub fn get_ptx_functions<P: AsRef<Path>>(filename: P) -> ResultAny<Vec<String>> {
-    let path = filename.as_ref().to_str().unwrap();
-    let md = Module::read_bitcode(path)?;
+    let md = Module::read_bitcode(&filename)?;
     let ptx: Vec<_> = md
         .functions()
         .iter()
         .filter(|f| f.is_ptx_kernel() || f.is_ptx_device_func())
         .map(|f| f.name())
         .collect();
-    if ptx.len() == 0 {
+    if ptx.is_empty() {
         return Err(err_msg("No PTX found"));
     }
     Ok(ptx)
 }
+
+/// Kernel functions (`extern "ptx-kernel"`) in the bitcode
+pub fn get_ptx_kernels<P: AsRef<Path>>(filename: P) -> ResultAny<Vec<String>> {
+    let md = Module::read_bitcode(&filename)?;
+    Ok(md
+        .functions()
+        .iter()
+        .filter(|f| f.is_ptx_kernel())
+        .map(|f| f.name())
+        .collect())
+}
+
+/// Undefined symbols in the bitcode, see [Module::undefined_symbols]
+pub fn get_undefined_symbols<P: AsRef<Path>>(filename: P) -> ResultAny<Vec<String>> {
+    Ok(Module::read_bitcode(filename)?.undefined_symbols())
+}
+
+/// Attach launch bounds to kernels as `!nvvm.annotations`, and overwrite the bitcode
+pub fn set_launch_bounds<P: AsRef<Path>>(
+    filename: P,
+    bounds: &BTreeMap<String, LaunchBounds>,
+) -> ResultAny<()> {
+    let md = Module::read_bitcode(&filename)?;
+    md.set_launch_bounds(bounds)?;
+    md.write_bitcode(filename)
+}
+
+/// Rename kernels by the export names, overwrite the bitcode, and returns the renamed symbols
+pub fn rename_functions<P: AsRef<Path>>(
+    filename: P,
+    names: &BTreeMap<String, String>,
+) -> ResultAny<Vec<(String, String)>> {
+    let md = Module::read_bitcode(&filename)?;
+    let renamed = md.rename(names)?;
+    md.write_bitcode(filename)?;
+    Ok(renamed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    /// Write LLVM IR as a bitcode file
+    fn assemble(ir: &str, path: &Path) {
+        Module::parse_ir(ir).unwrap().write_bitcode(path).unwrap();
+    }
+
+    fn print(md: &Module) -> String {
+        unsafe {
+            let ir = LLVMPrintModuleToString(md.0);
+            let s = CStr::from_ptr(ir).to_str().unwrap().to_string();
+            LLVMDisposeMessage(ir);
+            s
+        }
+    }
+
+    const IR: &str = r#"
+target triple = "nvptx64-nvidia-cuda"
+
+define ptx_kernel void @add(float* %a) {
+  ret void
+}
+
+define ptx_device void @helper() {
+  ret void
+}
+"#;
+
+    #[test]
+    fn launch_bounds() {
+        let dir = TempDir::new("nvptx-bitcode").unwrap();
+        let path = dir.path().join("kernel.bc");
+        assemble(IR, &path);
+        let mut bounds = BTreeMap::new();
+        bounds.insert(
+            "add".to_string(),
+            LaunchBounds {
+                maxntid: Some(vec![128, 2]),
+                minctasm: Some(4),
+                ..Default::default()
+            },
+        );
+        set_launch_bounds(&path, &bounds).unwrap();
+        let ir = print(&Module::read_bitcode(&path).unwrap());
+        assert!(ir.contains(r#"@add, !"maxntidx", i32 128}"#), "{}", ir);
+        assert!(ir.contains(r#"@add, !"maxntidy", i32 2}"#));
+        assert!(ir.contains(r#"@add, !"minctasm", i32 4}"#));
+
+        // Mangled kernels are specified by the demangled path
+        let scale = "_ZN6kernel5scale17h0123456789abcdefE";
+        assemble(
+            &format!("define ptx_kernel void @{}() {{\n  ret void\n}}\n", scale),
+            &path,
+        );
+        let mut demangled = BTreeMap::new();
+        demangled.insert(
+            "kernel::scale".to_string(),
+            LaunchBounds {
+                maxntid: Some(vec![64]),
+                ..Default::default()
+            },
+        );
+        set_launch_bounds(&path, &demangled).unwrap();
+        let ir = print(&Module::read_bitcode(&path).unwrap());
+        assert!(
+            ir.contains(&format!(r#"@{}, !"maxntidx", i32 64}}"#, scale)),
+            "{}",
+            ir
+        );
+
+        assemble(IR, &path);
+        bounds.insert("helper".to_string(), LaunchBounds::default());
+        let err = set_launch_bounds(&path, &bounds).unwrap_err();
+        assert_eq!(err.to_string(), "Kernel `helper` is not found");
+    }
+
+    #[test]
+    fn inspect() {
+        let module = Module::read(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/add.ll")).unwrap();
+        assert_eq!(module.target_triple(), "nvptx64-nvidia-cuda");
+        let err = Module::parse_ir("define void @f(").err().unwrap();
+        assert!(err.to_string().starts_with("Cannot parse LLVM IR: "));
+        let err = Module::read_bitcode("/nonexistent/kernel.bc")
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().starts_with("Cannot read input: "),
+            "{}",
+            err
+        );
+        let functions = module.functions();
+        let tid = &functions[0];
+        assert_eq!(tid.name(), "llvm.nvvm.read.ptx.sreg.tid.x");
+        assert!(tid.is_declaration() && tid.is_intrinsic());
+        assert!(tid.attributes().contains(&"nounwind".to_string()));
+        assert!(module.undefined_symbols().is_empty());
+        let square = module.function("square").unwrap();
+        assert!(!square.is_declaration());
+        assert_eq!(square.calling_convention(), "ccc");
+        assert_eq!(square.linkage(), "external");
+        let globals: Vec<_> = module
+            .globals()
+            .iter()
+            .map(|g| (g.name(), address_space_name(g.address_space()), g.linkage()))
+            .collect();
+        assert_eq!(
+            globals,
+            vec![
+                ("table".to_string(), "global", "external"),
+                ("cache".to_string(), "shared", "internal"),
+                ("scale".to_string(), "const", "external"),
+            ]
+        );
+    }
+
+    #[test]
+    fn undefined_symbols() {
+        let module = Module::parse_ir(
+            r#"
+target triple = "nvptx64-nvidia-cuda"
+
+@dynamic = external addrspace(3) global [0 x float]
+@params = external addrspace(4) global i32
+@table = external addrspace(1) global i32
+@counter = external global i32
+
+declare void @helper()
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            module.undefined_symbols(),
+            vec!["helper", "table", "counter"]
+        );
+    }
+
+    #[test]
+    fn rename() {
+        let dir = TempDir::new("nvptx-bitcode").unwrap();
+        let path = dir.path().join("kernel.bc");
+        assemble(
+            r#"
+define ptx_kernel void @_ZN6kernel3add17h3f5a7c8e2d1b9a04E() {
+  ret void
+}
+define ptx_kernel void @sub() {
+  ret void
+}
+"#,
+            &path,
+        );
+        let mut names = BTreeMap::new();
+        names.insert("kernel::add".to_string(), "add".to_string());
+        let renamed = rename_functions(&path, &names).unwrap();
+        assert_eq!(
+            renamed,
+            vec![(
+                "_ZN6kernel3add17h3f5a7c8e2d1b9a04E".to_string(),
+                "add".to_string()
+            )]
+        );
+        assert_eq!(get_ptx_kernels(&path).unwrap(), vec!["add", "sub"]);
+
+        names.insert("add".to_string(), "sub".to_string());
+        let err = rename_functions(&path, &names).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot export `add` as `sub`, which is already defined"
+        );
+    }
+}
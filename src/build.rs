@@ -0,0 +1,212 @@
+//! Compile a kernel crate in `build.rs` of the host crate
+//!
+//! ```no_run
+//! // in build.rs of the host crate
+//! nvptx::build::Builder::new("kernel").build().unwrap();
+//! ```
+//!
+//! The PTX is written into `OUT_DIR`, and can be embedded in the host crate:
+//!
+//! ```ignore
+//! const PTX: &str = include_str!(concat!(env!("OUT_DIR"), "/kernel.ptx"));
+//! ```
+//!
+//! Settings are taken from the environment variables:
+//!
+//! - `PROFILE`: release build if `release`
+//! - `OUT_DIR`: output directory of the PTX
+//! - `NVPTX_ARCH`: target GPU architecture (e.g. `sm_60`)
+//! - `NVPTX_TOOLCHAIN`: toolchain to build the kernel crate
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::*;
+
+use super::save_str;
+use crate::driver::Driver;
+use crate::error::*;
+use crate::manifest::{CargoTOML, Crate};
+use crate::output::BuildOutput;
+
+/// Cache file of `nvptx_macro::include_ptx!` in the kernel crate
+pub const INCLUDE_PTX_CACHE: &str = "target/include_ptx.json";
+
+/// Builder of a kernel crate for build scripts
+pub struct Builder {
+    path: PathBuf,
+    name: Option<String>,
+    arch: Option<String>,
+    toolchain: Option<String>,
+    out_dir: Option<PathBuf>,
+}
+
+impl Builder {
+    /// Kernel crate at the path. Relative path is resolved from `CARGO_MANIFEST_DIR`
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = match env::var_os("CARGO_MANIFEST_DIR") {
+            Some(dir) => PathBuf::from(dir).join(path),
+            None => path.as_ref().to_owned(),
+        };
+        Builder {
+            path,
+            name: None,
+            arch: None,
+            toolchain: None,
+            out_dir: None,
+        }
+    }
+
+    /// Name of the PTX file (`{name}.ptx`). Default is the directory name of the kernel crate.
+    pub fn set_name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn set_arch(&mut self, arch: &str) -> &mut Self {
+        self.arch = Some(arch.into());
+        self
+    }
+
+    pub fn set_toolchain(&mut self, toolchain: &str) -> &mut Self {
+        self.toolchain = Some(toolchain.into());
+        self
+    }
+
+    /// Output directory instead of `OUT_DIR`
+    pub fn set_out_dir<P: AsRef<Path>>(&mut self, out_dir: P) -> &mut Self {
+        self.out_dir = Some(out_dir.as_ref().to_owned());
+        self
+    }
+
+    /// Compile the kernel crate, and write PTX into `OUT_DIR`.
+    ///
+    /// Errors are also reported as `cargo:warning`.
+    pub fn build(&self) -> Result<BuildOutput> {
+        let res = self.try_build();
+        if let Err(e) = &res {
+            for line in format!("{}", e).lines() {
+                println!("cargo:warning={}", line);
+            }
+        }
+        res
+    }
+
+    fn try_build(&self) -> Result<BuildOutput> {
+        for path in
+            dependency_source_files(&self.path).log(Step::Ready, "Cannot read kernel crate")?
+        {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+        println!("cargo:rerun-if-env-changed=NVPTX_ARCH");
+        println!("cargo:rerun-if-env-changed=NVPTX_TOOLCHAIN");
+
+        let mut driver = Driver::with_path(&self.path)?;
+        if let Some(arch) = self.arch.clone().or_else(|| env::var("NVPTX_ARCH").ok()) {
+            driver.set_arch(&arch);
+        }
+        if let Some(toolchain) = self
+            .toolchain
+            .clone()
+            .or_else(|| env::var("NVPTX_TOOLCHAIN").ok())
+        {
+            driver.set_toolchain(&toolchain);
+        }
+        if env::var("PROFILE").map(|p| p == "release").unwrap_or(false) {
+            driver.release_build();
+        }
+        let output = driver.compile()?;
+
+        let out_dir = match &self.out_dir {
+            Some(out_dir) => out_dir.clone(),
+            None => env::var_os("OUT_DIR")
+                .map(PathBuf::from)
+                .ok_or_else(|| err_msg(Step::Ready, "OUT_DIR is not set"))?,
+        };
+        save_str(&out_dir, &output.ptx, &format!("{}.ptx", self.name()))
+            .log(Step::Load, "Failed to write PTX into OUT_DIR")?;
+        Ok(output)
+    }
+
+    fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("kernel")
+                .into(),
+        }
+    }
+}
+
+/// Cargo.toml and all files in `src/` of the crate
+pub fn source_files(path: &Path) -> ResultAny<Vec<PathBuf>> {
+    let mut files = vec![path.join("Cargo.toml")];
+    let mut dirs = vec![path.join("src")];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Source files of the crate and its path dependencies (e.g. `nvptx-panic`), recursively
+pub fn dependency_source_files(path: &Path) -> ResultAny<Vec<PathBuf>> {
+    let mut visited = BTreeSet::new();
+    let mut files = Vec::new();
+    let mut crates = vec![path.to_owned()];
+    while let Some(path) = crates.pop() {
+        let path = fs::canonicalize(&path)?;
+        if !visited.insert(path.clone()) {
+            continue;
+        }
+        files.extend(source_files(&path)?);
+        let manifest = CargoTOML::load(&path)?;
+        let patches = manifest
+            .patch
+            .values()
+            .flatten()
+            .map(|(name, dep)| Crate::from_dependency(name, dep));
+        for c in manifest.crates().into_iter().chain(patches) {
+            if let Some(dep) = c.path {
+                crates.push(path.join(dep));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_sources() {
+        let files = source_files(Path::new("example")).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("example/Cargo.toml"),
+                PathBuf::from("example/src/lib.rs")
+            ]
+        );
+    }
+
+    #[test]
+    fn path_dependency_sources() {
+        let files = dependency_source_files(Path::new("example")).unwrap();
+        let panic = fs::canonicalize("nvptx-panic/src/lib.rs").unwrap();
+        assert!(files.contains(&panic));
+        assert!(files.contains(&fs::canonicalize("example/src/lib.rs").unwrap()));
+    }
+}
@@ -0,0 +1,195 @@
+//! Call graph of kernels in the linked bitcode
+//!
+//! ```no_run
+//! use nvptx::bitcode::Module;
+//! use nvptx::callgraph::CallGraph;
+//!
+//! let module = Module::read_bitcode("target/nvptx64-nvidia-cuda/release/kernel.opt.bc").unwrap();
+//! let graph = CallGraph::new(&module);
+//! for kernel in graph.kernels() {
+//!     println!("{}: {} instructions", kernel, graph.size(kernel));
+//! }
+//! ```
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write;
+
+use crate::bitcode::Module;
+use crate::symbol;
+
+/// Function in the call graph
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Node {
+    pub name: String,
+    /// Demangled path of the name, or the name itself if not mangled
+    pub demangled: String,
+    pub kernel: bool,
+    /// Declared without definition, e.g. intrinsics
+    pub declaration: bool,
+    /// Number of LLVM instructions
+    pub instructions: usize,
+    /// Functions called directly, in the order of the first call
+    pub callees: Vec<String>,
+    /// Number of calls through function pointers, which are not followed
+    pub indirect_calls: usize,
+}
+
+/// Direct calls between functions in a module
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CallGraph {
+    pub functions: BTreeMap<String, Node>,
+}
+
+/// Functions reachable from a kernel, for `nvptx callgraph --format json`
+#[derive(Debug, Clone, Serialize)]
+pub struct KernelReport<'a> {
+    pub kernel: &'a str,
+    pub demangled: &'a str,
+    /// Total number of instructions of the reachable functions
+    pub instructions: usize,
+    pub functions: Vec<&'a Node>,
+}
+
+impl CallGraph {
+    pub fn new(module: &Module) -> Self {
+        let functions = module
+            .functions()
+            .iter()
+            .map(|f| {
+                let instructions = f.instructions();
+                let name = f.name();
+                let node = Node {
+                    demangled: symbol::demangle(&name),
+                    kernel: f.is_ptx_kernel(),
+                    declaration: f.is_declaration(),
+                    instructions: instructions.len(),
+                    callees: f.callees().iter().map(|g| g.name()).collect(),
+                    indirect_calls: instructions.iter().filter(|i| i.is_indirect_call()).count(),
+                    name: name.clone(),
+                };
+                (name, node)
+            })
+            .collect();
+        CallGraph { functions }
+    }
+
+    /// Names of the kernels
+    pub fn kernels(&self) -> Vec<&str> {
+        self.functions
+            .values()
+            .filter(|f| f.kernel)
+            .map(|f| f.name.as_str())
+            .collect()
+    }
+
+    /// Kernel named by the symbol or its demangled path
+    pub fn find_kernel(&self, name: &str) -> Option<&Node> {
+        self.functions
+            .values()
+            .find(|f| f.kernel && symbol::matches(&f.name, name))
+    }
+
+    /// Functions reachable from the root in breadth-first order, including the root itself
+    pub fn reachable(&self, root: &str) -> Vec<&Node> {
+        let mut visited = BTreeSet::new();
+        let mut queue: VecDeque<&str> = VecDeque::new();
+        let mut nodes = Vec::new();
+        queue.push_back(root);
+        while let Some(name) = queue.pop_front() {
+            let node = match self.functions.get(name) {
+                Some(node) if visited.insert(name) => node,
+                _ => continue,
+            };
+            queue.extend(node.callees.iter().map(|c| c.as_str()));
+            nodes.push(node);
+        }
+        nodes
+    }
+
+    /// Total number of instructions of the functions reachable from the root
+    pub fn size(&self, root: &str) -> usize {
+        self.reachable(root).iter().map(|f| f.instructions).sum()
+    }
+
+    pub fn report<'a>(&'a self, kernel: &'a Node) -> KernelReport<'a> {
+        let mut functions = self.reachable(&kernel.name);
+        functions.sort_by_key(|f| std::cmp::Reverse(f.instructions));
+        KernelReport {
+            kernel: &kernel.name,
+            demangled: &kernel.demangled,
+            instructions: functions.iter().map(|f| f.instructions).sum(),
+            functions,
+        }
+    }
+
+    /// Graphviz DOT of the functions reachable from the roots
+    pub fn to_dot(&self, roots: &[&str]) -> String {
+        let mut nodes = BTreeMap::new();
+        for root in roots {
+            for node in self.reachable(root) {
+                nodes.insert(node.name.as_str(), node);
+            }
+        }
+        let mut dot = String::from("digraph callgraph {\n    node [shape=box];\n");
+        for node in nodes.values() {
+            let style = if node.kernel {
+                ", style=bold"
+            } else if node.declaration {
+                ", style=dashed"
+            } else {
+                ""
+            };
+            writeln!(
+                dot,
+                "    {:?} [label={:?}{}];",
+                node.name,
+                format!("{}\n{}", node.demangled, node.instructions),
+                style
+            )
+            .unwrap();
+        }
+        for node in nodes.values() {
+            for callee in &node.callees {
+                writeln!(dot, "    {:?} -> {:?};", node.name, callee).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixture() {
+        let module = Module::read(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/add.ll")).unwrap();
+        let graph = CallGraph::new(&module);
+        // `add` is a kernel by `!nvvm.annotations`, not by the calling convention
+        assert!(graph.kernels().is_empty());
+        let names: Vec<_> = graph
+            .reachable("add")
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "add",
+                "llvm.nvvm.read.ptx.sreg.tid.x",
+                "llvm.nvvm.read.ptx.sreg.ctaid.x",
+                "llvm.nvvm.read.ptx.sreg.ntid.x",
+                "square",
+                "llvm.nvvm.barrier0",
+            ]
+        );
+        assert_eq!(graph.functions["square"].instructions, 2);
+        assert_eq!(graph.size("square"), 2);
+        assert_eq!(
+            graph.to_dot(&["square"]),
+            "digraph callgraph {\n    node [shape=box];\n    \"square\" [label=\"square\\n2\"];\n}\n"
+        );
+    }
+}
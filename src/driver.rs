@@ -3,14 +3,29 @@ use dirs::home_dir;
 use failure::err_msg;
 use log::*;
 use serde_json::{self, Value};
+use std::collections::BTreeMap;
 use std::io::Read;
 use std::path::*;
 use std::str::from_utf8;
-use std::{fs, io, process};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use std::{env, fs, io, process, thread};
 use tempdir::TempDir;
 
 use super::*;
 use error::*;
+use info::KernelInfo;
+use kernel::Kernel;
+use lint::Level;
+use llvm::{Discovery, Tool, Tools};
+use manifest::{LaunchBounds, NvptxMetadata, WriteMode};
+use output::BuildOutput;
+use ptx::Version;
+use state::BuildState;
+use symbol::KernelFilter;
+use target::Target;
+use timings::Timings;
 
 /// Compile Rust string into PTX string
 pub struct Driver {
@@ -18,7 +33,22 @@ pub struct Driver {
     release: bool,
     toolchain: String,
     arch: String,
+    target: Target,
     prefix: String,
+    sysroot: Option<PathBuf>,
+    llvm: Discovery,
+    llvm_tools: Mutex<Option<Tools>>,
+    rlibs: Mutex<Option<Vec<PathBuf>>>,
+    jobs: usize,
+    timings: Mutex<Timings>,
+    manifest_mode: WriteMode,
+    ptx_version: Option<Version>,
+    ptx_target: Option<String>,
+    launch_bounds: BTreeMap<String, LaunchBounds>,
+    export_names: BTreeMap<String, String>,
+    include_kernels: Vec<String>,
+    exclude_kernels: Vec<String>,
+    lints: BTreeMap<String, Level>,
 }
 
 impl Driver {
@@ -43,7 +73,22 @@ impl Driver {
             release: false,
             toolchain: TOOLCHAIN_NAME.into(),
             arch: "sm_50".into(),
+            target: Target::default(),
             prefix: "kernel".into(),
+            sysroot: None,
+            llvm: Discovery::default(),
+            llvm_tools: Mutex::new(None),
+            rlibs: Mutex::new(None),
+            jobs: default_jobs(),
+            timings: Mutex::new(Timings::default()),
+            manifest_mode: WriteMode::default(),
+            ptx_version: None,
+            ptx_target: None,
+            launch_bounds: BTreeMap::new(),
+            export_names: BTreeMap::new(),
+            include_kernels: Vec::new(),
+            exclude_kernels: Vec::new(),
+            lints: BTreeMap::new(),
         })
     }
 
@@ -55,36 +100,228 @@ impl Driver {
         self.arch = arch.into();
     }
 
+    /// Target name (e.g. `nvptx-nvidia-cuda`) or path of target specification JSON
+    pub fn set_target(&mut self, target: &str) {
+        self.target = Target::new(target);
+    }
+
+    pub fn target(&self) -> &Target {
+        &self.target
+    }
+
+    /// Target GPU architecture, e.g. `sm_50`
+    pub fn arch(&self) -> &str {
+        &self.arch
+    }
+
     pub fn release_build(&mut self) {
         self.release = true;
     }
 
+    /// Number of concurrent jobs for converting rlibs (default: number of CPUs)
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.jobs = jobs.max(1);
+    }
+
+    /// Timings of the stages executed by this driver
+    pub fn timings(&self) -> Timings {
+        self.timings.lock().unwrap().clone()
+    }
+
+    /// Use a sysroot built by `sysroot::Sysroot` instead of the toolchain's one.
+    /// The sysroot registered for the toolchain by `Sysroot::build` is used if not set.
+    pub fn set_sysroot<P: AsRef<Path>>(&mut self, sysroot: P) {
+        self.sysroot = Some(sysroot.as_ref().to_owned());
+    }
+
+    /// Sysroot set by `set_sysroot`, or the one registered for the toolchain and target
+    pub fn sysroot(&self) -> Option<PathBuf> {
+        self.sysroot
+            .clone()
+            .or_else(|| sysroot::registered(Some(&self.toolchain), &self.target.name()))
+    }
+
+    /// Use the specified command for an LLVM tool instead of searching it
+    pub fn set_llvm_tool<P: AsRef<Path>>(&mut self, tool: Tool, path: P) {
+        self.llvm.set_tool(tool, path);
+        *self.llvm_tools.get_mut().unwrap() = None;
+    }
+
+    /// Search LLVM tools in `llvm-config --bindir` of the specified `llvm-config`
+    pub fn set_llvm_config<P: AsRef<Path>>(&mut self, llvm_config: P) {
+        self.llvm.set_llvm_config(llvm_config);
+        *self.llvm_tools.get_mut().unwrap() = None;
+    }
+
+    /// Search LLVM tools in `bin/` of the specified LLVM root directory
+    pub fn set_llvm_root<P: AsRef<Path>>(&mut self, root: P) {
+        self.llvm.set_root(root);
+        *self.llvm_tools.get_mut().unwrap() = None;
+    }
+
+    /// Version suffixes of LLVM tools searched in `PATH` (default: `["6.0", "7.0"]`)
+    pub fn set_llvm_suffixes(&mut self, suffixes: &[&str]) {
+        self.llvm.set_suffixes(suffixes);
+        *self.llvm_tools.get_mut().unwrap() = None;
+    }
+
+    /// Setting for resolving LLVM tools, e.g. to build a sysroot with the same tools
+    pub fn llvm(&self) -> &Discovery {
+        &self.llvm
+    }
+
+    /// Resolved LLVM tools. The result is cached after the first call.
+    pub fn llvm_tools(&self) -> Result<Tools> {
+        if let Some(tools) = self.llvm_tools.lock().unwrap().as_ref() {
+            return Ok(tools.clone());
+        }
+        let tools = self
+            .llvm
+            .resolve()
+            .log(Step::Ready, "Cannot find LLVM tools")?;
+        info!("LLVM tools = {:?}", tools);
+        *self.llvm_tools.lock().unwrap() = Some(tools.clone());
+        Ok(tools)
+    }
+
     pub fn path(&self) -> &Path {
         &self.path
     }
 
-    pub fn compile(&self) -> Result<()> {
+    /// Build the crate and link it into PTX
+    pub fn compile(&self) -> Result<BuildOutput> {
         self.build()?;
         self.link()?;
-        Ok(())
+        self.output()
     }
 
+    /// Rewrite `.version` of the generated PTX, e.g. to load it on an older CUDA driver
+    pub fn set_ptx_version(&mut self, version: Version) {
+        self.ptx_version = Some(version);
+    }
+
+    /// Rewrite `.target` of the generated PTX, e.g. `sm_35`
+    pub fn set_ptx_target(&mut self, target: &str) {
+        self.ptx_target = Some(target.into());
+    }
+
+    /// Launch bounds of the kernel, used instead of `[package.metadata.nvptx.kernels.<name>]`
+    pub fn set_launch_bounds(&mut self, kernel: &str, bounds: LaunchBounds) {
+        self.launch_bounds.insert(kernel.into(), bounds);
+    }
+
+    /// Export the kernel as the name in PTX. The kernel is specified by its symbol or
+    /// demangled path (e.g. `kernel::add`), used with `[package.metadata.nvptx.export]`
+    pub fn set_export_name(&mut self, kernel: &str, name: &str) {
+        self.export_names.insert(kernel.into(), name.into());
+    }
+
+    /// Glob patterns of exported kernels, in addition to `include-kernels` and `exclude-kernels`
+    /// in `[package.metadata.nvptx]`. Other kernels are dropped from PTX.
+    pub fn set_kernel_patterns(&mut self, include: &[&str], exclude: &[&str]) {
+        self.include_kernels = include.iter().map(|p| p.to_string()).collect();
+        self.exclude_kernels = exclude.iter().map(|p| p.to_string()).collect();
+    }
+
+    /// Level of the lint (e.g. `recursion`), used instead of `[package.metadata.nvptx.lints]`
+    pub fn set_lint_level(&mut self, lint: &str, level: Level) {
+        self.lints.insert(lint.into(), level);
+    }
+
+    /// How `compile_kernel` writes Cargo.toml if the crate already has one
+    pub fn set_manifest_mode(&mut self, mode: WriteMode) {
+        self.manifest_mode = mode;
+    }
+
+    /// Compile kernel source with its dependencies into PTX.
+    ///
+    /// Cargo.toml is generated with the dependencies and runtime setting of the kernel,
+    /// see [Kernel](../kernel/struct.Kernel.html).
+    pub fn compile_kernel(&self, kernel: &Kernel) -> Result<String> {
+        manifest::write(
+            &self.path,
+            kernel.crates(),
+            &kernel.runtimes(),
+            self.manifest_mode,
+        )?;
+        self.compile_str(&kernel.source())
+    }
+
+    /// Compile kernel source into PTX. Cargo.toml must be prepared by `manifest::generate`.
+    /// Outputs of the previous compilation are reused if the inputs are unchanged.
     pub fn compile_str(&self, kernel: &str) -> Result<String> {
         save_str(&self.path, kernel, "src/lib.rs").log(Step::Ready, "Failed to save lib.rs")?;
         self.format();
-        self.clean();
         self.compile()?;
         self.load_ptx()
     }
 
+    /// Build the crate using cargo, and record the rlibs of the crate and its dependencies
     pub fn build(&self) -> Result<()> {
         let mut cmd = process::Command::new("cargo");
-        cmd.arg(format!("+{}", self.toolchain))
-            .args(&["build", "--target", TARGET_NAME]);
+        cmd.arg(format!("+{}", self.toolchain)).args([
+            "build",
+            "--message-format=json",
+            "--target",
+            &self.target.as_arg(),
+        ]);
         if self.release {
             cmd.arg("--release");
         }
-        cmd.current_dir(&self.path).check_run(Step::Build)
+        // Variables set by outer cargo (e.g. for build scripts) must not override the toolchain
+        for var in &["RUSTC", "RUSTC_WRAPPER", "RUSTDOC"] {
+            cmd.env_remove(var);
+        }
+        if let Some(sysroot) = self.sysroot() {
+            // RUSTFLAGS is applied only to the target crates (not to build scripts) with --target.
+            // Keep the user's flags, which cargo reads from CARGO_ENCODED_RUSTFLAGS first.
+            let mut flags: Vec<String> = match env::var("CARGO_ENCODED_RUSTFLAGS") {
+                Ok(encoded) => encoded
+                    .split('\x1f')
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.to_string())
+                    .collect(),
+                Err(_) => env::var("RUSTFLAGS")
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(|s| s.to_string())
+                    .collect(),
+            };
+            flags.push("--sysroot".into());
+            flags.push(sysroot.to_str().unwrap().into());
+            cmd.env("CARGO_ENCODED_RUSTFLAGS", flags.join("\x1f"));
+        }
+        let start = Instant::now();
+        let mut rlibs = Vec::new();
+        cmd.current_dir(&self.path).check_run_lines(
+            Step::Build,
+            |line| match parse_cargo_message(line) {
+                CargoMessage::Artifact(paths) => rlibs.extend(paths),
+                CargoMessage::Diagnostic(rendered) => eprint!("{}", rendered),
+                CargoMessage::Other => {}
+                CargoMessage::NotJson => println!("{}", line),
+            },
+        )?;
+        *self.rlibs.lock().unwrap() = Some(rlibs);
+        self.timings.lock().unwrap().build = Some(start.elapsed());
+        Ok(())
+    }
+
+    /// rlibs for the target generated by the last `build` in dependency order.
+    /// The crate is built if not yet.
+    fn built_rlibs(&self, target_dir: &Path) -> Result<Vec<PathBuf>> {
+        if self.rlibs.lock().unwrap().is_none() {
+            self.build()?;
+        }
+        let mut rlibs: Vec<PathBuf> = Vec::new();
+        for rlib in self.rlibs.lock().unwrap().as_ref().unwrap() {
+            // Skip rlibs for host, e.g. dependencies of proc-macro crates
+            let rlib = fs::canonicalize(rlib).log(Step::Link, "rlib not found")?;
+            if rlib.starts_with(target_dir) && !rlibs.contains(&rlib) {
+                rlibs.push(rlib);
+            }
+        }
+        Ok(rlibs)
     }
 
     fn target_dir(&self) -> io::Result<PathBuf> {
@@ -94,7 +331,7 @@ impl Driver {
     fn target_dir_name(&self) -> String {
         format!(
             "target/{}/{}",
-            TARGET_NAME,
+            self.target.name(),
             if self.release { "release" } else { "debug" }
         )
     }
@@ -115,22 +352,94 @@ impl Driver {
         format!("{}.cubin", self.prefix)
     }
 
+    fn state_name(&self) -> String {
+        format!("{}.state.json", self.prefix)
+    }
+
     /// Link rlib into a single PTX file
     pub fn link(&self) -> Result<()> {
         let target_dir = self.target_dir().log_unwrap(Step::Link)?;
+        let tools = self.llvm_tools()?;
+        let rt = self
+            .get_runtime_setting()
+            .log(Step::Link, "Fail to load package.metadata.nvptx.runtime")?;
+        let runtimes = self
+            .get_compiler_rt(&rt)
+            .log(Step::Link, "Fail to get copiler-rt libs")?;
+        let setting = self
+            .get_link_setting()
+            .log(Step::Link, "Fail to load package.metadata.nvptx")?;
+        let filter = KernelFilter::new(&setting.include_kernels, &setting.exclude_kernels)
+            .log(Step::Link, "Invalid pattern of kernels")?;
 
-        // List bitcodes
-        let bitcodes: ResultAny<Vec<PathBuf>> = fs::read_dir(target_dir.join("deps"))
-            .log(Step::Link, "deps dir not found")?
-            .filter_map(|entry| {
-                let path = entry.unwrap().path();
-                if path.extension()? == "rlib" {
-                    Some(rlib2bc(&path))
-                } else {
-                    None
-                }
-            })
-            .collect();
+        let state_path = target_dir.join(self.state_name());
+        let prev = BuildState::load(&state_path);
+        let mut state = BuildState::default();
+        state.setting("llvm-link", tools.llvm_link.display());
+        state.setting("opt", tools.opt.display());
+        state.setting("llc", tools.llc.display());
+        state.setting("llvm-major", tools.major);
+        state.setting("arch", &self.arch);
+        state.setting("release", self.release);
+        state.setting("target", self.target.as_arg());
+        state.setting(
+            "kernels",
+            serde_json::to_string(&setting.kernels).log_unwrap(Step::Link)?,
+        );
+        state.setting(
+            "export",
+            serde_json::to_string(&setting.export).log_unwrap(Step::Link)?,
+        );
+        state.setting("include-kernels", setting.include_kernels.join(","));
+        state.setting("exclude-kernels", setting.exclude_kernels.join(","));
+        state.setting(
+            "lints",
+            serde_json::to_string(&setting.lints).log_unwrap(Step::Link)?,
+        );
+        if let Some(version) = &self.ptx_version {
+            state.setting("ptx-version", version);
+        }
+        if let Some(target) = &self.ptx_target {
+            state.setting("ptx-target", target);
+        }
+
+        {
+            let mut timings = self.timings.lock().unwrap();
+            timings.link = None;
+            timings.internalize = None;
+            timings.codegen = None;
+        }
+
+        // Convert rlibs into bitcodes if changed
+        let rlibs = self.built_rlibs(&target_dir)?;
+        let mut stale = Vec::new();
+        for path in &rlibs {
+            let fresh = state
+                .track(path, &prev)
+                .log(Step::Link, "Cannot read rlib")?;
+            if !fresh || !rlib_bc_path(path).exists() {
+                stale.push(path.clone());
+            }
+        }
+        let start = Instant::now();
+        rlib2bc_all(&stale, &tools.llvm_link, self.jobs)
+            .log(Step::Link, "Fail to convert to LLVM BC")?;
+        self.timings.lock().unwrap().rlib2bc = Some(start.elapsed());
+        let bitcodes: Vec<_> = rlibs.iter().map(|path| rlib_bc_path(path)).collect();
+        for path in &runtimes {
+            state
+                .track(path, &prev)
+                .log(Step::Link, "Cannot read runtime bitcode")?;
+        }
+        if state == prev && target_dir.join(self.ptx_name()).exists() {
+            eprintln!(
+                "{:>12} PTX code ({}/{})",
+                "Fresh".bright_green(),
+                self.target_dir_name(),
+                self.ptx_name()
+            );
+            return Ok(());
+        }
 
         // Link Rust runtime libraries
         eprintln!(
@@ -139,15 +448,30 @@ impl Driver {
             self.target_dir_name(),
             self.bitcode_name()
         );
-        let rt = self
-            .get_runtime_setting()
-            .log(Step::Link, "Fail to load package.metadata.nvptx.runtime")?;
-        process::Command::new(llvm_command("llvm-link").log(Step::Link, "llvm-link not found")?)
-            .args(&bitcodes.log(Step::Link, "Fail to convert to LLVM BC")?)
-            .args(get_compiler_rt(&rt).log(Step::Link, "Fail to get copiler-rt libs")?)
-            .args(&["-o", &self.bitcode_name()])
+        let start = Instant::now();
+        process::Command::new(&tools.llvm_link)
+            .args(&bitcodes)
+            .args(&runtimes)
+            .args(["-o", &self.bitcode_name()])
             .current_dir(&target_dir)
             .check_run(Step::Link)?;
+        self.timings.lock().unwrap().link = Some(start.elapsed());
+
+        // Rename kernels before they are used as the public API list
+        let mut renamed = Vec::new();
+        if !setting.export.is_empty() {
+            renamed =
+                bitcode::rename_functions(target_dir.join(self.bitcode_name()), &setting.export)
+                    .log(Step::Link, "Fail to rename kernels")?;
+            for (symbol, name) in &renamed {
+                eprintln!(
+                    "{:>12} {} as {}",
+                    "Exporting".bright_green(),
+                    symbol::demangle(symbol),
+                    name
+                );
+            }
+        }
 
         // Internalize unused symbols
         eprintln!(
@@ -156,9 +480,31 @@ impl Driver {
             self.target_dir_name(),
             self.opt_bc_name()
         );
-        let ptx_funcs = bitcode::get_ptx_functions(&target_dir.join(self.bitcode_name()))
-            .log(Step::Link, "Fail to parse LLVM bitcode")?;
-        process::Command::new(llvm_command("opt").log(Step::Link, "opt not found")?)
+        let start = Instant::now();
+        let bitcode = target_dir.join(self.bitcode_name());
+        let kernels =
+            bitcode::get_ptx_kernels(&bitcode).log(Step::Link, "Fail to parse LLVM bitcode")?;
+        let dropped = dropped_kernels(&filter, &kernels, &renamed);
+        let launch_bounds = resolve_launch_bounds(&setting.kernels, &kernels, &dropped, &renamed)?;
+        if !kernels.is_empty() && dropped.len() == kernels.len() {
+            return Err(error::err_msg(
+                Step::Link,
+                "All kernels are dropped by include-kernels and exclude-kernels",
+            ));
+        }
+        for kernel in &dropped {
+            eprintln!(
+                "{:>12} kernel {}",
+                "Excluding".bright_green(),
+                symbol::demangle(kernel)
+            );
+        }
+        let ptx_funcs: Vec<String> = bitcode::get_ptx_functions(&bitcode)
+            .log(Step::Link, "Fail to parse LLVM bitcode")?
+            .into_iter()
+            .filter(|f| !dropped.contains(f))
+            .collect();
+        process::Command::new(&tools.opt)
             .arg("-internalize")
             .arg(format!(
                 "-internalize-public-api-list={}",
@@ -168,6 +514,39 @@ impl Driver {
             .args(&[&self.bitcode_name(), "-o", &self.opt_bc_name()])
             .current_dir(&target_dir)
             .check_run(Step::Link)?;
+        self.timings.lock().unwrap().internalize = Some(start.elapsed());
+        for kernel in bitcode::get_ptx_kernels(target_dir.join(self.opt_bc_name()))
+            .log(Step::Link, "Fail to parse LLVM bitcode")?
+            .iter()
+            .filter(|name| symbol::is_mangled(name))
+        {
+            eprintln!(
+                "{}: kernel `{}` is exported as mangled symbol `{}`. \
+                 Use #[no_mangle] or set its name in [package.metadata.nvptx.export]",
+                "warning".bright_yellow(),
+                symbol::demangle(kernel),
+                kernel
+            );
+        }
+        let undefined = bitcode::get_undefined_symbols(target_dir.join(self.opt_bc_name()))
+            .log(Step::Link, "Fail to parse LLVM bitcode")?;
+        check_undefined(&undefined, &rt)?;
+        self.lint(&target_dir.join(self.opt_bc_name()), &setting.lints)?;
+
+        // Attach launch bounds as `!nvvm.annotations`
+        if !launch_bounds.is_empty() {
+            eprintln!(
+                "{:>12} launch bounds ({})",
+                "Annotating".bright_green(),
+                launch_bounds
+                    .keys()
+                    .map(|k| symbol::demangle(k))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            bitcode::set_launch_bounds(target_dir.join(self.opt_bc_name()), &launch_bounds)
+                .log(Step::Link, "Fail to set launch bounds")?;
+        }
 
         // Generate PTX
         eprintln!(
@@ -176,16 +555,78 @@ impl Driver {
             self.target_dir_name(),
             self.ptx_name()
         );
-        process::Command::new(llvm_command("llc").log_unwrap(Step::Link)?)
+        let start = Instant::now();
+        process::Command::new(&tools.llc)
             .arg(if self.release { "-O3" } else { "-O0" })
             .arg(format!("-mcpu={}", self.arch))
             .args(&[&self.opt_bc_name(), "-o", &self.ptx_name()])
             .current_dir(&target_dir)
             .check_run(Step::Link)?;
+        self.timings.lock().unwrap().codegen = Some(start.elapsed());
+
+        if self.ptx_version.is_some() || self.ptx_target.is_some() {
+            self.retarget(&target_dir.join(self.ptx_name()))?;
+        }
+
+        state
+            .save(&state_path)
+            .log(Step::Link, "Fail to save build state")?;
         Ok(())
     }
 
-    pub fn cubin(&self) -> Result<()> {
+    /// Report lints of the bitcode, and fails if some of them are denied
+    fn lint(&self, path: &Path, levels: &BTreeMap<String, Level>) -> Result<()> {
+        let module =
+            bitcode::Module::read_bitcode(path).log(Step::Link, "Fail to parse LLVM bitcode")?;
+        let diagnostics = lint::check(&module, levels).log(Step::Link, "Invalid lint setting")?;
+        for d in &diagnostics {
+            let level = match d.level {
+                Level::Deny => "error".bright_red(),
+                _ => "warning".bright_yellow(),
+            };
+            eprintln!("{}: {}", level, d);
+        }
+        let denied = diagnostics
+            .iter()
+            .filter(|d| d.level == Level::Deny)
+            .count();
+        if denied > 0 {
+            return Err(error::err_msg(
+                Step::Link,
+                &format!(
+                    "{} lint(s) denied in [package.metadata.nvptx.lints]",
+                    denied
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Rewrite `.version` and `.target` of the generated PTX
+    fn retarget(&self, path: &Path) -> Result<()> {
+        let ptx = fs::read_to_string(path).log(Step::Link, "Cannot read PTX")?;
+        let mut module = ptx::Module::parse(&ptx).log(Step::Link, "Fail to parse generated PTX")?;
+        retarget::retarget(&mut module, self.ptx_version, self.ptx_target.as_deref())
+            .log(Step::Link, "Invalid PTX version or target")?;
+        let version = module
+            .version()
+            .ok_or_else(|| error::err_msg(Step::Link, "PTX has no .version directive"))?;
+        let arch = module
+            .target()
+            .and_then(|t| t.first())
+            .ok_or_else(|| error::err_msg(Step::Link, "PTX has no .target directive"))?;
+        eprintln!(
+            "{:>12} PTX code (ISA {}, {})",
+            "Retargeting".bright_green(),
+            version,
+            arch
+        );
+        fs::write(path, module.to_string()).log(Step::Link, "Cannot write PTX")?;
+        Ok(())
+    }
+
+    /// Convert PTX into cubin, and returns its path
+    pub fn cubin(&self) -> Result<PathBuf> {
         let target_dir = self.target_dir().log_unwrap(Step::Convert)?;
         eprintln!(
             "{:>12} to cubin ({}/{})",
@@ -193,12 +634,14 @@ impl Driver {
             self.target_dir_name(),
             self.cubin_name()
         );
+        let start = Instant::now();
         process::Command::new("nvcc")
             .arg(format!("-arch={}", self.arch))
             .args(&["--cubin", &self.ptx_name(), "-o", &self.cubin_name()])
             .current_dir(&target_dir)
             .check_run(Step::Convert)?;
-        Ok(())
+        self.timings.lock().unwrap().cubin = Some(start.elapsed());
+        Ok(target_dir.join(self.cubin_name()))
     }
 
     pub fn load_ptx(&self) -> Result<String> {
@@ -210,12 +653,108 @@ impl Driver {
         Ok(res)
     }
 
-    fn clean(&self) {
-        let path = self.path.join("target");
-        match fs::remove_dir_all(&path) {
-            Ok(_) => {}
-            Err(_) => info!("already clean (dir = {})", path.display()),
+    /// Resource usage of the kernels in the generated PTX
+    pub fn kernel_info(&self) -> Result<Vec<KernelInfo>> {
+        let ptx = self.load_ptx()?;
+        let module = ptx::Module::parse(&ptx).log(Step::Load, "Fail to parse PTX")?;
+        Ok(info::kernel_info(&module))
+    }
+
+    /// Call graph of the linked bitcode after internalize and globaldce
+    pub fn call_graph(&self) -> Result<callgraph::CallGraph> {
+        let target_dir = self.target_dir().log_unwrap(Step::Load)?;
+        let module = bitcode::Module::read_bitcode(target_dir.join(self.opt_bc_name()))
+            .log(Step::Load, "Fail to read LLVM bitcode")?;
+        Ok(callgraph::CallGraph::new(&module))
+    }
+
+    /// Artifacts of the last compilation
+    pub fn output(&self) -> Result<BuildOutput> {
+        let target_dir = self.target_dir().log_unwrap(Step::Load)?;
+        let opt_bitcode = target_dir.join(self.opt_bc_name());
+        let kernels =
+            bitcode::get_ptx_kernels(&opt_bitcode).log(Step::Load, "Fail to parse LLVM bitcode")?;
+        let ptx_path = target_dir.join(self.ptx_name());
+        let cubin = target_dir.join(self.cubin_name());
+        // cubin older than PTX is a stale one
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        let cubin = match (modified(&cubin), modified(&ptx_path)) {
+            (Some(c), Some(p)) if c >= p => Some(cubin),
+            _ => None,
         };
+        Ok(BuildOutput {
+            bitcode: target_dir.join(self.bitcode_name()),
+            opt_bitcode,
+            ptx_path,
+            ptx: self.load_ptx()?,
+            cubin,
+            demangled_kernels: kernels.iter().map(|k| symbol::demangle(k)).collect(),
+            kernels,
+            arch: self.arch.clone(),
+            target: self.target.clone(),
+            toolchain: self.toolchain.clone(),
+            llvm: self.llvm_tools()?,
+            release: self.release,
+            timings: self.timings(),
+            target_dir,
+        })
+    }
+
+    /// Remove files generated for nvptx target, and returns the removed paths
+    pub fn clean(&self, opts: &CleanOptions) -> Result<Vec<PathBuf>> {
+        let nvptx_dir = self.path.join("target").join(self.target.name());
+        let mut paths = Vec::new();
+        if opts.target_dir {
+            if nvptx_dir.exists() {
+                paths.push(nvptx_dir.clone());
+            }
+        } else {
+            let mut names = Vec::new();
+            if opts.artifacts {
+                names.push(self.bitcode_name());
+                names.push(self.opt_bc_name());
+                names.push(self.ptx_name());
+                names.push(self.cubin_name());
+            }
+            if opts.cache {
+                names.push(self.state_name());
+            }
+            for profile in &["debug", "release"] {
+                let dir = nvptx_dir.join(profile);
+                paths.extend(
+                    names
+                        .iter()
+                        .map(|name| dir.join(name))
+                        .filter(|p| p.exists()),
+                );
+                if opts.cache {
+                    paths.extend(files_with_extension(&dir.join("deps"), "bc"));
+                }
+            }
+        }
+        if opts.cache {
+            let cache = self.path.join(build::INCLUDE_PTX_CACHE);
+            if cache.exists() {
+                paths.push(cache);
+            }
+        }
+        if opts.sysroot {
+            if let Some(sysroot) = self.sysroot().or_else(|| sysroot::default_path().ok()) {
+                let lib_dir = sysroot::lib_dir(&sysroot, &self.target.name());
+                paths.extend(files_with_extension(&lib_dir, "bc"));
+            }
+        }
+        if !opts.dry_run {
+            for path in &paths {
+                if path.is_dir() {
+                    fs::remove_dir_all(path)
+                } else {
+                    fs::remove_file(path)
+                }
+                .log(Step::Ready, &format!("Failed to remove {}", path.display()))?;
+            }
+        }
+        Ok(paths)
     }
 
     // Format generated code using cargo-fmt for better debugging
@@ -230,23 +769,48 @@ impl Driver {
         }
     }
 
-    /// Runtime setting is writen in Cargo.toml like
-    ///
-    /// ```text
-    /// [package.metadata.nvptx]
-    /// runtime = ["core"]
-    /// ```
-    fn get_runtime_setting(&self) -> ResultAny<Vec<String>> {
+    /// Runtime bitcodes, searched in the sysroot of the driver first
+    fn get_compiler_rt(&self, runtimes: &[String]) -> ResultAny<Vec<PathBuf>> {
+        let dirs = toolchain::runtime_dirs(self.sysroot().as_deref(), &self.target.name());
+        toolchain::find_compiler_rt(&dirs, runtimes)
+    }
+
+    /// `[package.metadata.nvptx]` of the crate, or `null` if not found
+    fn get_nvptx_metadata(&self) -> ResultAny<Value> {
         let output = process::Command::new("cargo")
             .args(&["metadata", "--no-deps", "--format-version=1"])
             .current_dir(&self.path)
             .output()?;
         let json = from_utf8(&output.stdout)?;
         if json.len() == 0 {
-            return Ok(Vec::new());
+            return Ok(Value::Null);
         }
         let meta: Value = serde_json::from_str(json)?;
-        Ok(match meta.pointer("/packages/0/metadata/nvptx/runtime") {
+        // Select the package of this crate, since a workspace contains several packages
+        let manifest = fs::canonicalize(self.path.join("Cargo.toml"))?;
+        let package = meta["packages"]
+            .as_array()
+            .and_then(|packages| {
+                packages.iter().find(|p| {
+                    p["manifest_path"]
+                        .as_str()
+                        .and_then(|path| fs::canonicalize(path).ok())
+                        .map(|path| path == manifest)
+                        .unwrap_or(false)
+                })
+            })
+            .unwrap_or(&meta["packages"][0]);
+        Ok(package["metadata"]["nvptx"].clone())
+    }
+
+    /// Runtime setting is writen in Cargo.toml like
+    ///
+    /// ```text
+    /// [package.metadata.nvptx]
+    /// runtime = ["core"]
+    /// ```
+    fn get_runtime_setting(&self) -> ResultAny<Vec<String>> {
+        Ok(match self.get_nvptx_metadata()?.get("runtime") {
             Some(rt) => {
                 let rt = rt
                     .as_array()
@@ -262,14 +826,222 @@ impl Driver {
             None => Vec::new(),
         })
     }
+
+    /// Settings in `[package.metadata.nvptx]` used in the link step,
+    /// overwritten or extended by the ones set to the driver
+    fn get_link_setting(&self) -> ResultAny<NvptxMetadata> {
+        let mut meta = match self.get_nvptx_metadata()? {
+            Value::Null => NvptxMetadata::default(),
+            meta => serde_json::from_value(meta)?,
+        };
+        meta.kernels.extend(self.launch_bounds.clone());
+        meta.export.extend(self.export_names.clone());
+        meta.include_kernels
+            .extend(self.include_kernels.iter().cloned());
+        meta.exclude_kernels
+            .extend(self.exclude_kernels.iter().cloned());
+        meta.lints.extend(self.lints.clone());
+        Ok(meta)
+    }
+}
+
+/// Kernels dropped by include-kernels and exclude-kernels. The patterns are matched against
+/// the symbols before renamed by `[package.metadata.nvptx.export]`.
+fn dropped_kernels(
+    filter: &KernelFilter,
+    kernels: &[String],
+    renamed: &[(String, String)],
+) -> Vec<String> {
+    kernels
+        .iter()
+        .filter(|kernel| !filter.is_exported(original_symbol(kernel, renamed)))
+        .cloned()
+        .collect()
+}
+
+/// Symbol of the kernel before renamed by `[package.metadata.nvptx.export]`
+fn original_symbol<'a>(kernel: &'a str, renamed: &'a [(String, String)]) -> &'a str {
+    renamed
+        .iter()
+        .find(|(_, name)| name == kernel)
+        .map_or(kernel, |(symbol, _)| symbol.as_str())
+}
+
+/// Launch bounds keyed by the symbols of the kept kernels. Kernels are specified by the symbol
+/// or demangled path, either before or after renamed. Bounds of the dropped kernels are ignored
+/// with warnings, and names matching no kernel are errors.
+fn resolve_launch_bounds(
+    bounds: &BTreeMap<String, LaunchBounds>,
+    kernels: &[String],
+    dropped: &[String],
+    renamed: &[(String, String)],
+) -> Result<BTreeMap<String, LaunchBounds>> {
+    let mut resolved = BTreeMap::new();
+    for (name, bounds) in bounds {
+        let matched: Vec<&String> = kernels
+            .iter()
+            .filter(|k| {
+                symbol::matches(k, name) || symbol::matches(original_symbol(k, renamed), name)
+            })
+            .collect();
+        if matched.is_empty() {
+            return Err(error::err_msg(
+                Step::Link,
+                &format!("Kernel `{}` is not found for launch bounds", name),
+            ));
+        }
+        for kernel in matched {
+            if dropped.contains(kernel) {
+                eprintln!(
+                    "{}: launch bounds of kernel `{}` are ignored since it is excluded",
+                    "warning".bright_yellow(),
+                    symbol::demangle(kernel)
+                );
+            } else {
+                resolved.insert(kernel.clone(), bounds.clone());
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+/// Fail if the symbols are not resolved by CUDA, and suggest runtime crates defining them.
+/// Undefined symbols are otherwise reported by llc or CUDA JIT with cryptic messages.
+fn check_undefined(symbols: &[String], runtimes: &[String]) -> Result<()> {
+    let mut runtime = runtimes.to_vec();
+    let unresolved: Vec<String> = symbols
+        .iter()
+        .filter(|s| !symbol::is_cuda_symbol(s))
+        .map(|s| match symbol::runtime_crate(s) {
+            Some(krate) => {
+                if !runtime.iter().any(|rt| rt == krate) {
+                    runtime.push(krate.into());
+                }
+                format!("{} ({})", symbol::demangle(s), krate)
+            }
+            None => symbol::demangle(s),
+        })
+        .collect();
+    if unresolved.is_empty() {
+        return Ok(());
+    }
+    let mut comment = format!("Undefined symbols: {}", unresolved.join(", "));
+    if runtime.len() > runtimes.len() {
+        comment += &format!(
+            ". Add runtime crates to [package.metadata.nvptx] in Cargo.toml: runtime = {:?}",
+            runtime
+        );
+    }
+    Err(error::err_msg(Step::Link, &comment))
+}
+
+/// Files removed by `Driver::clean`
+#[derive(Debug, Clone, Default)]
+pub struct CleanOptions {
+    /// `target/{target}` directory, containing all outputs for nvptx target
+    pub target_dir: bool,
+    /// Linked bitcode, PTX and cubin (`kernel.{bc,opt.bc,ptx,cubin}`)
+    pub artifacts: bool,
+    /// Runtime bitcodes converted in the sysroot
+    pub sysroot: bool,
+    /// Build state, bitcodes converted from rlibs, and the cache of `include_ptx!`
+    pub cache: bool,
+    /// List the files without removing them
+    pub dry_run: bool,
+}
+
+/// Files with the extension in the directory, empty if the directory does not exist
+fn files_with_extension(dir: &Path, ext: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && path.extension().is_some_and(|e| e == ext))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    files.sort();
+    files
+}
+
+enum CargoMessage {
+    /// rlibs generated by cargo
+    Artifact(Vec<PathBuf>),
+    /// Rendered compiler message
+    Diagnostic(String),
+    Other,
+    NotJson,
+}
+
+/// Parse an output line of `cargo build --message-format=json`
+fn parse_cargo_message(line: &str) -> CargoMessage {
+    let msg: Value = match serde_json::from_str(line) {
+        Ok(msg) => msg,
+        Err(_) => return CargoMessage::NotJson,
+    };
+    match msg["reason"].as_str() {
+        Some("compiler-artifact") => CargoMessage::Artifact(
+            msg["filenames"]
+                .as_array()
+                .map(|names| {
+                    names
+                        .iter()
+                        .filter_map(|name| name.as_str())
+                        .filter(|name| name.ends_with(".rlib"))
+                        .map(PathBuf::from)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        ),
+        Some("compiler-message") => match msg.pointer("/message/rendered") {
+            Some(Value::String(rendered)) => CargoMessage::Diagnostic(rendered.clone()),
+            _ => CargoMessage::Other,
+        },
+        _ => CargoMessage::Other,
+    }
+}
+
+/// Default number of jobs, i.e. the available parallelism
+pub fn default_jobs() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Expand rlibs into LLVM/BC binaries using `jobs` threads concurrently
+pub fn rlib2bc_all(rlibs: &[PathBuf], llvm_link: &Path, jobs: usize) -> ResultAny<Vec<PathBuf>> {
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<ResultAny<PathBuf>>>> =
+        Mutex::new(rlibs.iter().map(|_| None).collect());
+    thread::scope(|s| {
+        for _ in 0..jobs.max(1).min(rlibs.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= rlibs.len() {
+                    break;
+                }
+                let res = rlib2bc(&rlibs[i], llvm_link);
+                results.lock().unwrap()[i] = Some(res);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|res| res.unwrap())
+        .collect()
+}
+
+/// Path of LLVM/BC binary generated from the rlib by `rlib2bc`
+pub fn rlib_bc_path(path: &Path) -> PathBuf {
+    path.with_extension("bc")
 }
 
 /// Expand rlib into a linked LLVM/BC binary (*.bc)
-pub fn rlib2bc(path: &Path) -> ResultAny<PathBuf> {
-    let parent = path.parent().unwrap_or(Path::new(""));
-    let name = path.file_stem().unwrap();
+pub fn rlib2bc(path: &Path, llvm_link: &Path) -> ResultAny<PathBuf> {
     let dir = TempDir::new("rlib2bc")?;
-    let target = parent.join(format!("{}.bc", name.to_str().unwrap()));
+    let target = rlib_bc_path(path);
 
     // `ar xv some.rlib` expand rlib and show its compnent
     let output = process::Command::new("ar")
@@ -287,7 +1059,7 @@ pub fn rlib2bc(path: &Path) -> ResultAny<PathBuf> {
         .iter()
         .filter(|line| line.ends_with(".rcgu.o"))
         .collect(); // FIXME filtering using suffix will cause compiler dependency
-    let ec = process::Command::new(llvm_command("llvm-link")?)
+    let ec = process::Command::new(llvm_link)
         .args(&bcs)
         .arg("-o")
         .arg(&target)
@@ -299,38 +1071,104 @@ pub fn rlib2bc(path: &Path) -> ResultAny<PathBuf> {
     Ok(target)
 }
 
-/// Check if the command exists using "--help" flag
-fn check_exists(name: &str) -> bool {
-    process::Command::new(name)
-        .arg("--help")
-        .stdout(process::Stdio::null())
-        .stderr(process::Stdio::null())
-        .check_run(Step::Ready)
-        .is_ok()
-}
-
-/// Resolve LLVM command name with postfix
-fn llvm_command(name: &str) -> ResultAny<String> {
-    let name6 = format!("{}-6.0", name);
-    let name7 = format!("{}-7.0", name);
-    if check_exists(&name6) {
-        Ok(name6)
-    } else if check_exists(&name7) {
-        Ok(name7)
-    } else if check_exists(&name) {
-        Ok(name.into())
-    } else {
-        Err(err_msg(
-            "LLVM Command {} or postfixed by *-6.0 or *-7.0 are not found.",
-        ))
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use manifest;
 
+    #[test]
+    fn cargo_message() {
+        let artifact = r#"{"reason":"compiler-artifact","package_id":"accel-core 0.2.0","target":{"kind":["lib"],"name":"accel_core"},"filenames":["/tmp/target/nvptx64-nvidia-cuda/debug/deps/libaccel_core-0123abcd.rlib"],"fresh":true}"#;
+        match parse_cargo_message(artifact) {
+            CargoMessage::Artifact(rlibs) => assert_eq!(
+                rlibs,
+                vec![PathBuf::from(
+                    "/tmp/target/nvptx64-nvidia-cuda/debug/deps/libaccel_core-0123abcd.rlib"
+                )]
+            ),
+            _ => panic!("Artifact is not detected"),
+        }
+        let proc_macro = r#"{"reason":"compiler-artifact","target":{"kind":["proc-macro"]},"filenames":["/tmp/target/debug/deps/libderive-0123.so"]}"#;
+        match parse_cargo_message(proc_macro) {
+            CargoMessage::Artifact(rlibs) => assert!(rlibs.is_empty()),
+            _ => panic!("Artifact is not detected"),
+        }
+        let warning = r#"{"reason":"compiler-message","message":{"rendered":"warning: unused\n"}}"#;
+        match parse_cargo_message(warning) {
+            CargoMessage::Diagnostic(rendered) => assert_eq!(rendered, "warning: unused\n"),
+            _ => panic!("Diagnostic is not detected"),
+        }
+    }
+
+    #[test]
+    fn export_with_exclude_kernels() {
+        let filter = KernelFilter::new(&[], &["accel_core::*".into(), "add".into()]).unwrap();
+        let fill = "_ZN10accel_core4fill17h3f5a7c8e2d1b9a04E";
+        let add = "_ZN6kernel3add17h0123456789abcdefE";
+        let renamed = vec![
+            (fill.to_string(), "fill".to_string()),
+            (add.to_string(), "add".to_string()),
+        ];
+        let kernels = vec!["add".to_string(), "fill".to_string(), "scale".to_string()];
+        // `fill` is excluded by its original path, and `add` is kept since only its new name matches
+        assert_eq!(dropped_kernels(&filter, &kernels, &renamed), vec!["fill"]);
+        assert_eq!(
+            dropped_kernels(&filter, &kernels, &[]),
+            vec!["add".to_string()]
+        );
+    }
+
+    #[test]
+    fn launch_bounds_of_excluded_kernels() {
+        let add = "_ZN6kernel3add17h0123456789abcdefE";
+        let kernels = vec!["add".to_string(), "fill".to_string()];
+        let renamed = vec![(add.to_string(), "add".to_string())];
+        let dropped = vec!["fill".to_string()];
+        let bounds = |names: &[&str]| -> BTreeMap<String, LaunchBounds> {
+            names
+                .iter()
+                .map(|name| (name.to_string(), LaunchBounds::default()))
+                .collect()
+        };
+        let resolved = resolve_launch_bounds(
+            &bounds(&["kernel::add", "fill"]),
+            &kernels,
+            &dropped,
+            &renamed,
+        )
+        .unwrap();
+        assert_eq!(resolved.keys().collect::<Vec<_>>(), vec!["add"]);
+        let err =
+            resolve_launch_bounds(&bounds(&["scale"]), &kernels, &dropped, &renamed).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Kernel `scale` is not found for launch bounds"));
+    }
+
+    #[test]
+    fn clean() {
+        let dri = Driver::new().unwrap();
+        let debug = dri.path().join("target/nvptx64-nvidia-cuda/debug");
+        fs::create_dir_all(debug.join("deps")).unwrap();
+        for name in &["kernel.ptx", "kernel.state.json", "deps/libcore-0123.bc"] {
+            fs::write(debug.join(name), "").unwrap();
+        }
+        let mut opts = CleanOptions {
+            artifacts: true,
+            dry_run: true,
+            ..Default::default()
+        };
+        assert_eq!(dri.clean(&opts).unwrap(), vec![debug.join("kernel.ptx")]);
+        assert!(debug.join("kernel.ptx").exists());
+
+        opts.dry_run = false;
+        opts.cache = true;
+        assert_eq!(dri.clean(&opts).unwrap().len(), 3);
+        assert!(!debug.join("kernel.ptx").exists());
+        assert!(!debug.join("deps/libcore-0123.bc").exists());
+        assert!(debug.exists());
+    }
+
     #[test]
     fn get_runtime_here() {
         let driver = Driver::with_path(".").unwrap();
@@ -348,4 +1186,30 @@ mod tests {
             .expect("Failed to get runtime setting");
         assert_eq!(rt, Vec::<String>::new());
     }
+
+    #[test]
+    fn driver_is_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Driver>();
+    }
+
+    #[test]
+    fn undefined_symbols() {
+        let core = vec!["core".to_string()];
+        let symbols = vec!["vprintf".to_string()];
+        assert!(check_undefined(&symbols, &core).is_ok());
+        let symbols = vec![
+            "_ZN4core9panicking5panic17h3f5a7c8e2d1b9a04E".to_string(),
+            "__udivti3".to_string(),
+        ];
+        match check_undefined(&symbols, &core) {
+            Err(CompileError::OtherError { comment, .. }) => assert_eq!(
+                comment,
+                "Undefined symbols: core::panicking::panic (core), __udivti3 (compiler_builtins). \
+                 Add runtime crates to [package.metadata.nvptx] in Cargo.toml: \
+                 runtime = [\"core\", \"compiler_builtins\"]"
+            ),
+            _ => panic!("Undefined symbols must be reported"),
+        }
+    }
 }
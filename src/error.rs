@@ -1,4 +1,5 @@
 use failure::Fail;
+use std::io::BufRead;
 use std::{io, process};
 
 #[derive(Debug, Clone, Copy)]
@@ -82,33 +83,59 @@ impl<T, E: Into<failure::Error>> Logging for ::std::result::Result<T, E> {
 
 pub trait CheckRun {
     fn check_run(&mut self, step: Step) -> Result<()>;
+
+    /// Run the command, and pass each line of its stdout to `f`
+    fn check_run_lines<F: FnMut(&str)>(&mut self, step: Step, f: F) -> Result<()>;
 }
 
 impl CheckRun for process::Command {
     fn check_run(&mut self, step: Step) -> Result<()> {
-        let st = self.status().map_err(|error| {
-            let command = format!("{:?}", self);
-            CompileError::CommandIOFailure {
-                step,
-                command,
-                error,
-            }
-        })?;
-        match st.code() {
-            Some(error_code) => {
-                if error_code != 0 {
-                    let command = format!("{:?}", self);
-                    Err(CompileError::CommandFailure {
-                        step,
-                        command,
-                        error_code,
-                    }
-                    .into())
-                } else {
-                    Ok(())
-                }
+        let st = self
+            .status()
+            .map_err(|error| io_failure(self, step, error))?;
+        check_status(self, step, st)
+    }
+
+    fn check_run_lines<F: FnMut(&str)>(&mut self, step: Step, mut f: F) -> Result<()> {
+        let mut child = self
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .map_err(|error| io_failure(self, step, error))?;
+        let stdout = io::BufReader::new(child.stdout.take().unwrap());
+        for line in stdout.lines() {
+            let line = line.map_err(|error| io_failure(self, step, error))?;
+            f(&line);
+        }
+        let st = child
+            .wait()
+            .map_err(|error| io_failure(self, step, error))?;
+        check_status(self, step, st)
+    }
+}
+
+fn io_failure(cmd: &process::Command, step: Step, error: io::Error) -> CompileError {
+    let command = format!("{:?}", cmd);
+    CompileError::CommandIOFailure {
+        step,
+        command,
+        error,
+    }
+}
+
+fn check_status(cmd: &process::Command, step: Step, st: process::ExitStatus) -> Result<()> {
+    match st.code() {
+        Some(error_code) => {
+            if error_code != 0 {
+                let command = format!("{:?}", cmd);
+                Err(CompileError::CommandFailure {
+                    step,
+                    command,
+                    error_code,
+                })
+            } else {
+                Ok(())
             }
-            None => Ok(()),
         }
+        None => Ok(()),
     }
 }
@@ -0,0 +1,207 @@
+//! Resource usage of kernels in PTX
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::ptx::{Function, Module, Operand, StateSpace, Variable};
+use crate::symbol;
+
+/// Resources statically allocated for a kernel
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KernelInfo {
+    pub name: String,
+    /// Demangled path of the name, e.g. `kernel::add`, or the name itself if not mangled
+    pub demangled: String,
+    /// Number of declared virtual registers for each type, e.g. `{"b32": 7}`
+    pub registers: BTreeMap<String, u32>,
+    /// Static `.shared` memory in bytes, including module-level variables used by the kernel
+    pub shared: u64,
+    /// Uses dynamically sized `.extern .shared` array
+    pub dynamic_shared: bool,
+    /// `.local` memory in bytes, including device functions called from the kernel
+    pub local: u64,
+    /// Size of `.param` space in bytes
+    pub params: u64,
+    pub maxntid: Option<Vec<u32>>,
+    pub reqntid: Option<Vec<u32>>,
+}
+
+impl KernelInfo {
+    /// Total number of declared virtual registers
+    pub fn total_registers(&self) -> u32 {
+        self.registers.values().sum()
+    }
+
+    /// Check the static shared memory fits in the limit of the architecture
+    pub fn exceeds_shared_limit(&self, arch: &str) -> bool {
+        self.shared > shared_memory_limit(arch)
+    }
+}
+
+/// Maximum static shared memory per block in bytes, e.g. 48KB for `sm_50`
+pub fn shared_memory_limit(arch: &str) -> u64 {
+    let version: u32 = arch
+        .trim_start_matches("sm_")
+        .trim_start_matches("compute_")
+        .trim_end_matches(char::is_alphabetic)
+        .parse()
+        .unwrap_or(50);
+    if version < 20 {
+        16 * 1024
+    } else {
+        // Larger shared memory on sm_70 or later is only available dynamically
+        48 * 1024
+    }
+}
+
+/// Resource usage of each kernel defined in the module
+pub fn kernel_info(module: &Module) -> Vec<KernelInfo> {
+    module
+        .kernels()
+        .map(|kernel| {
+            let called = reachable(module, kernel);
+            let used = |v: &&Variable| {
+                called
+                    .iter()
+                    .any(|f| f.instructions().any(|i| refers(&i.operands, &v.name)))
+            };
+            let module_vars: Vec<&Variable> = module.variables().filter(used).collect();
+            let local_vars: Vec<&Variable> = called.iter().flat_map(|f| f.variables()).collect();
+            let size_of = |space: StateSpace| -> u64 {
+                module_vars
+                    .iter()
+                    .chain(local_vars.iter())
+                    .filter(|v| v.space == space)
+                    .filter_map(|v| v.size())
+                    .sum()
+            };
+            let mut registers = BTreeMap::new();
+            for v in kernel.variables().filter(|v| v.space == StateSpace::Reg) {
+                *registers.entry(v.ty.clone()).or_insert(0) += v.count.unwrap_or(1);
+            }
+            KernelInfo {
+                name: kernel.name.clone(),
+                demangled: symbol::demangle(&kernel.name),
+                registers,
+                shared: size_of(StateSpace::Shared),
+                dynamic_shared: module_vars
+                    .iter()
+                    .chain(local_vars.iter())
+                    .any(|v| v.space == StateSpace::Shared && v.size().is_none()),
+                local: size_of(StateSpace::Local),
+                params: param_size(&kernel.params),
+                maxntid: kernel.performance("maxntid").map(|v| v.to_vec()),
+                reqntid: kernel.performance("reqntid").map(|v| v.to_vec()),
+            }
+        })
+        .collect()
+}
+
+/// Size of parameters placed with their alignment
+fn param_size(params: &[Variable]) -> u64 {
+    params.iter().fold(0, |offset, p| {
+        let size = p.size().unwrap_or(0);
+        let align = p.align.map(u64::from).unwrap_or(size).max(1);
+        offset.div_ceil(align) * align + size
+    })
+}
+
+/// The function and device functions called from it
+fn reachable<'a>(module: &'a Module, entry: &'a Function) -> Vec<&'a Function> {
+    let mut visited = BTreeSet::new();
+    let mut stack = vec![entry];
+    let mut functions = Vec::new();
+    while let Some(f) = stack.pop() {
+        if !visited.insert(f.name.as_str()) {
+            continue;
+        }
+        functions.push(f);
+        for inst in f.instructions().filter(|i| i.opcode == "call") {
+            for callee in module.functions().filter(|g| g.body.is_some()) {
+                if refers(&inst.operands, &callee.name) {
+                    stack.push(callee);
+                }
+            }
+        }
+    }
+    functions
+}
+
+fn refers(operands: &[Operand], name: &str) -> bool {
+    operands.iter().any(|op| match op {
+        Operand::Symbol(s) => s == name,
+        Operand::Address { base, .. } => base == name,
+        Operand::Vector(ops) | Operand::List(ops) => refers(ops, name),
+        Operand::Other(raw) => identifiers(raw).any(|id| id == name),
+        _ => false,
+    })
+}
+
+/// Identifiers in the raw operand, e.g. `buf` and `r1` in `[buf+%r1]`
+fn identifiers(raw: &str) -> impl Iterator<Item = &str> {
+    raw.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '%'))
+        .filter(|id| !id.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixture() {
+        let module = Module::parse(include_str!("../fixtures/add.ptx")).unwrap();
+        let info = kernel_info(&module);
+        assert_eq!(info.len(), 1);
+        let add = &info[0];
+        assert_eq!(add.name, "add");
+        assert_eq!(add.demangled, "add");
+        assert_eq!(add.total_registers(), 36);
+        assert_eq!(add.registers["f32"], 10);
+        assert_eq!(add.shared, 1024);
+        assert_eq!(add.params, 32);
+        assert!(!add.exceeds_shared_limit("sm_50"));
+    }
+
+    #[test]
+    fn shared_in_callee() {
+        let module = Module::parse(
+            r#"
+.shared .align 4 .b8 buf[40960];
+.shared .align 4 .b8 unused[40960];
+.func fill()
+{
+	.local .align 4 .b8 tmp[64];
+	.shared .align 4 .b8 scratch[16384];
+	st.shared.u32 [buf+4], 1;
+	ret;
+}
+.visible .entry k(
+	.param .u32 k_param_0,
+	.param .align 8 .b8 k_param_1[24]
+)
+.reqntid 128, 1, 1
+{
+	call.uni fill, ();
+	ret;
+}
+"#,
+        )
+        .unwrap();
+        let k = &kernel_info(&module)[0];
+        assert_eq!(k.shared, 40960 + 16384);
+        assert_eq!(k.local, 64);
+        assert_eq!(k.params, 32);
+        assert_eq!(k.reqntid, Some(vec![128, 1, 1]));
+        assert!(k.exceeds_shared_limit("sm_70"));
+        assert_eq!(shared_memory_limit("sm_13"), 16 * 1024);
+    }
+
+    #[test]
+    fn whole_identifier() {
+        let op = |raw: &str| vec![Operand::Other(raw.into())];
+        assert!(refers(&op("[buf+4]"), "buf"));
+        assert!(!refers(&op("[buf2+4]"), "buf"));
+        assert!(!refers(&op("%rbuf"), "buf"));
+        assert!(refers(&op("f$1, (%r1)"), "f$1"));
+    }
+}
@@ -0,0 +1,262 @@
+//! Kernel source with its dependencies for `Driver::compile_kernel`
+//!
+//! Dependencies are inferred from `extern crate` and `use` lines in the source:
+//!
+//! ```
+//! use nvptx::kernel::Kernel;
+//!
+//! let kernel = Kernel::new(r#"
+//! #![feature(abi_ptx)]
+//! #![no_std]
+//! extern crate accel_core;
+//!
+//! #[no_mangle]
+//! pub unsafe extern "ptx-kernel" fn add(a: *const f64, b: *const f64, c: *mut f64, n: usize) {
+//!     let i = accel_core::index();
+//!     if (i as usize) < n {
+//!         *c.offset(i) = *a.offset(i) + *b.offset(i);
+//!     }
+//! }
+//! "#);
+//! let crates: Vec<_> = kernel.crates().iter().map(|c| c.name.clone()).collect();
+//! assert_eq!(crates, vec!["accel-core", "nvptx-panic"]);
+//! assert_eq!(kernel.runtimes(), vec!["core"]);
+//! ```
+//!
+//! Inferred crate names use `-` instead of `_` (e.g. `accel_core` as `accel-core`).
+//! Names defined in the source (modules, items, and aliases by `as`) are not inferred as crates.
+//! Crates given explicitly by [Kernel::with_crates] are used prior to inferred ones.
+//!
+//! `nvptx-panic` is added if the source has no panic handler. The crates.io release pinned to
+//! the version of `nvptx-panic/` in this repository ([NVPTX_PANIC_VERSION]) is used by default,
+//! and a local checkout can be used instead by setting [NVPTX_PANIC_PATH_ENV] (`NVPTX_PANIC_PATH`)
+//! or giving `Crate::with_path("nvptx-panic", path)` to [Kernel::with_crates].
+
+use std::env;
+
+use crate::manifest::Crate;
+
+/// Crates always available without dependency
+const BUILTIN_CRATES: [&str; 6] = ["core", "alloc", "std", "crate", "self", "super"];
+
+/// Kernel source and its dependencies
+#[derive(Debug, Clone)]
+pub struct Kernel {
+    source: String,
+    crates: Vec<Crate>,
+}
+
+impl Kernel {
+    /// Kernel with dependencies inferred from the source
+    pub fn new(source: &str) -> Self {
+        Self::with_crates(source, &[])
+    }
+
+    /// Kernel with explicit dependencies in addition to the inferred ones
+    pub fn with_crates(source: &str, crates: &[Crate]) -> Self {
+        let mut kernel = Kernel {
+            source: source.into(),
+            crates: crates.to_vec(),
+        };
+        for name in used_crates(source) {
+            kernel.add_crate(Crate::latest(&name.replace('_', "-")));
+        }
+        if !has_panic_handler(source) {
+            kernel.add_crate(nvptx_panic());
+        }
+        kernel
+    }
+
+    fn add_crate(&mut self, c: Crate) {
+        let normalize = |name: &str| name.replace('-', "_");
+        if !self
+            .crates
+            .iter()
+            .any(|d| normalize(&d.name) == normalize(&c.name))
+        {
+            self.crates.push(c);
+        }
+    }
+
+    pub fn crates(&self) -> &[Crate] {
+        &self.crates
+    }
+
+    /// Runtime crates for `[package.metadata.nvptx] runtime`
+    pub fn runtimes(&self) -> Vec<String> {
+        let mut rt = vec!["core".to_string()];
+        if used_crates_with_builtin(&self.source)
+            .iter()
+            .any(|name| name == "alloc")
+        {
+            rt.push("alloc".into());
+        }
+        rt
+    }
+
+    /// Source to be saved as `src/lib.rs`.
+    /// `extern crate` is appended for the dependencies not declared in the source
+    /// since the crate is built as 2015 edition.
+    pub fn source(&self) -> String {
+        let declared: Vec<String> = self
+            .source
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("extern crate "))
+            .map(ident)
+            .collect();
+        let mut source = self.source.clone();
+        for c in &self.crates {
+            let name = c.name.replace('-', "_");
+            if !declared.contains(&name) {
+                source.push_str(&format!("\nextern crate {};\n", name));
+            }
+        }
+        source
+    }
+}
+
+/// Environment variable for the path of a local nvptx-panic crate, used instead of crates.io
+pub const NVPTX_PANIC_PATH_ENV: &str = "NVPTX_PANIC_PATH";
+
+/// Version of `nvptx-panic/` in this repository
+pub const NVPTX_PANIC_VERSION: &str = "0.1.1-alpha.0";
+
+/// nvptx-panic crate at `NVPTX_PANIC_PATH` if set, or the one on crates.io of the same version
+/// as this repository
+pub(crate) fn nvptx_panic() -> Crate {
+    match env::var_os(NVPTX_PANIC_PATH_ENV) {
+        Some(path) => Crate::with_path("nvptx-panic", path),
+        None => Crate::new("nvptx-panic", &format!("={}", NVPTX_PANIC_VERSION)),
+    }
+}
+
+fn has_panic_handler(source: &str) -> bool {
+    source.contains("#[panic_handler]")
+        || source.contains("\"panic_impl\"")
+        || source.contains("\"panic_fmt\"")
+}
+
+/// External crates used in the source
+fn used_crates(source: &str) -> Vec<String> {
+    used_crates_with_builtin(source)
+        .into_iter()
+        .filter(|name| !BUILTIN_CRATES.contains(&name.as_str()))
+        .collect()
+}
+
+/// Crates in `extern crate` and `use` lines, including `core` and `alloc`.
+/// Names defined in the source are excluded.
+fn used_crates_with_builtin(source: &str) -> Vec<String> {
+    let local = local_names(source);
+    let mut crates = Vec::new();
+    for line in source.lines() {
+        let line = strip_visibility(line.trim());
+        let name = if let Some(rest) = line.strip_prefix("extern crate ") {
+            ident(rest)
+        } else if let Some(rest) = line.strip_prefix("use ") {
+            ident(rest.trim_start_matches("::"))
+        } else {
+            continue;
+        };
+        if !name.is_empty() && !crates.contains(&name) {
+            crates.push(name);
+        }
+    }
+    crates.retain(|name| !local.contains(name));
+    crates
+}
+
+/// Modules, items, and aliases by `as` defined in the source
+fn local_names(source: &str) -> Vec<String> {
+    const ITEMS: [&str; 12] = [
+        "mod ",
+        "enum ",
+        "struct ",
+        "union ",
+        "trait ",
+        "type ",
+        "fn ",
+        "const fn ",
+        "const ",
+        "static mut ",
+        "static ",
+        "macro_rules! ",
+    ];
+    let mut names = Vec::new();
+    for line in source.lines() {
+        let mut line = strip_visibility(line.trim());
+        if line.starts_with("use ") || line.starts_with("extern crate ") {
+            names.extend(line.split(" as ").skip(1).map(|rest| ident(rest.trim())));
+            continue;
+        }
+        for qualifier in &["unsafe ", "async ", "extern \"C\" "] {
+            line = line.strip_prefix(qualifier).unwrap_or(line);
+        }
+        if let Some(rest) = ITEMS.iter().find_map(|item| line.strip_prefix(item)) {
+            names.push(ident(rest));
+        }
+    }
+    names.retain(|name| !name.is_empty());
+    names
+}
+
+/// Strip `pub`, `pub(crate)` and so on
+fn strip_visibility(line: &str) -> &str {
+    match line.strip_prefix("pub") {
+        Some(rest) if rest.starts_with('(') => match rest.find(')') {
+            Some(end) => rest[end + 1..].trim_start(),
+            None => line,
+        },
+        Some(rest) if rest.starts_with(' ') => rest.trim_start(),
+        _ => line,
+    }
+}
+
+/// Leading identifier of the string
+fn ident(s: &str) -> String {
+    s.chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::CargoTOML;
+
+    #[test]
+    fn nvptx_panic_version() {
+        let manifest =
+            CargoTOML::load(concat!(env!("CARGO_MANIFEST_DIR"), "/nvptx-panic")).unwrap();
+        assert_eq!(manifest.package.version, NVPTX_PANIC_VERSION);
+    }
+
+    #[test]
+    fn infer_crates() {
+        let source = r#"
+#![no_std]
+extern crate alloc;
+extern crate accel_core as accel;
+use core::ptr;
+use ::num_traits::Float;
+pub use self::util::*;
+use util::helper;
+use Color::*;
+use Shape as S;
+use S::Circle;
+mod util;
+pub(crate) enum Color { Red }
+pub struct Shape;
+const fn zero() -> u32 { 0 }
+use zero as z;
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! { loop {} }
+"#;
+        let kernel = Kernel::with_crates(source, &[Crate::new("num-traits", "0.2")]);
+        let crates: Vec<_> = kernel.crates().iter().map(|c| c.name.clone()).collect();
+        assert_eq!(crates, vec!["num-traits", "accel-core"]);
+        assert_eq!(kernel.crates()[0].version, Some("0.2".into()));
+        assert_eq!(kernel.runtimes(), vec!["core", "alloc"]);
+        assert!(kernel.source().ends_with("\nextern crate num_traits;\n"));
+    }
+}
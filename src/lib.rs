@@ -1,13 +1,29 @@
 //! Compile Rust into PTX string using LLVM
 
-mod bitcode;
+pub mod bitcode;
+pub mod build;
+pub mod callgraph;
 mod driver;
 pub mod error;
+pub mod info;
+pub mod kernel;
+pub mod lint;
+pub mod llvm;
 pub mod manifest;
+mod output;
+pub mod ptx;
+pub mod retarget;
+pub mod scaffold;
+mod state;
+pub mod symbol;
+pub mod sysroot;
+pub mod target;
+pub mod timings;
 mod toolchain;
 
-pub use driver::Driver;
-pub use toolchain::{get_compiler_rt, install};
+pub use driver::{default_jobs, CleanOptions, Driver};
+pub use output::BuildOutput;
+pub use toolchain::{get_all_compiler_rt, get_compiler_rt, install};
 
 use std::io::Write;
 use std::path::Path;
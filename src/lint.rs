@@ -0,0 +1,340 @@
+//! Lint constructs in the linked bitcode which compile but fail on GPU
+//!
+//! Each lint is reported for the kernels reaching the construct, and its level is configured
+//! in Cargo.toml:
+//!
+//! ```toml
+//! [package.metadata.nvptx.lints]
+//! recursion = "deny"
+//! i128 = "allow"
+//! ```
+//!
+//! All lints are `warn` by default.
+
+use failure::err_msg;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::bitcode::{Function, Module, Opcode};
+use crate::error::ResultAny;
+use crate::symbol;
+
+/// Intrinsics and functions not supported by NVPTX
+const UNSUPPORTED_FUNCTIONS: [&str; 6] = [
+    "abort",
+    "llvm.debugtrap",
+    "llvm.frameaddress",
+    "llvm.returnaddress",
+    "llvm.stacksave",
+    "llvm.stackrestore",
+];
+
+/// Prefixes of intrinsics for other targets or exception handling
+const UNSUPPORTED_PREFIXES: [&str; 7] = [
+    "llvm.eh.",
+    "llvm.x86.",
+    "llvm.arm.",
+    "llvm.aarch64.",
+    "llvm.amdgcn.",
+    "llvm.wasm.",
+    "llvm.riscv.",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Lint {
+    /// Calls to `abort` or intrinsics NVPTX does not support
+    UnsupportedIntrinsic,
+    /// `i128` multiplication, division or shift, which needs compiler-rt
+    I128,
+    /// Recursive calls, which needs the stack size set at launch
+    Recursion,
+    /// Calls through function pointers
+    FunctionPointer,
+    /// Mutable global variables in the generic address space (0).
+    /// Constants (e.g. panic locations) are not reported.
+    GenericGlobal,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 5] = [
+        Lint::UnsupportedIntrinsic,
+        Lint::I128,
+        Lint::Recursion,
+        Lint::FunctionPointer,
+        Lint::GenericGlobal,
+    ];
+
+    /// Name in `[package.metadata.nvptx.lints]`, e.g. `function-pointer`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UnsupportedIntrinsic => "unsupported-intrinsic",
+            Lint::I128 => "i128",
+            Lint::Recursion => "recursion",
+            Lint::FunctionPointer => "function-pointer",
+            Lint::GenericGlobal => "generic-global",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Allow,
+    #[default]
+    Warn,
+    Deny,
+}
+
+/// Lint found in a function reachable from a kernel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub lint: Lint,
+    pub level: Level,
+    pub kernel: String,
+    pub function: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "kernel `{}`: {} in `{}` [{}]",
+            symbol::demangle(&self.kernel),
+            self.message,
+            symbol::demangle(&self.function),
+            self.lint.name()
+        )
+    }
+}
+
+/// Lint the kernels in the module with the levels keyed by lint names.
+/// Lints at `allow` level are not reported.
+pub fn check(module: &Module, levels: &BTreeMap<String, Level>) -> ResultAny<Vec<Diagnostic>> {
+    let mut level_of = BTreeMap::new();
+    for (name, level) in levels {
+        let lint = Lint::ALL
+            .iter()
+            .find(|lint| lint.name() == name)
+            .ok_or_else(|| err_msg(format!("Unknown lint: {}", name)))?;
+        level_of.insert(*lint, *level);
+    }
+    let level = |lint: Lint| level_of.get(&lint).cloned().unwrap_or_default();
+
+    let mut diagnostics = Vec::new();
+    for kernel in module.functions().iter().filter(|f| f.is_ptx_kernel()) {
+        let mut found = Vec::new();
+        for f in reachable(kernel) {
+            found.extend(check_function(&f));
+        }
+        found.extend(recursion(kernel));
+        let mut seen = BTreeSet::new();
+        for (lint, function, message) in found {
+            if level(lint) == Level::Allow
+                || !seen.insert((lint, function.clone(), message.clone()))
+            {
+                continue;
+            }
+            diagnostics.push(Diagnostic {
+                lint,
+                level: level(lint),
+                kernel: kernel.name(),
+                function,
+                message,
+            });
+        }
+    }
+    Ok(diagnostics)
+}
+
+/// Defined functions reachable from the kernel by direct calls, including the kernel itself
+fn reachable<'m>(kernel: &Function<'m>) -> Vec<Function<'m>> {
+    let mut visited = BTreeSet::new();
+    let mut stack = vec![*kernel];
+    let mut functions = Vec::new();
+    while let Some(f) = stack.pop() {
+        if f.is_declaration() || !visited.insert(f.name()) {
+            continue;
+        }
+        stack.extend(f.callees());
+        functions.push(f);
+    }
+    functions
+}
+
+fn check_function(f: &Function) -> Vec<(Lint, String, String)> {
+    let mut found = Vec::new();
+    let name = f.name();
+    for inst in f.instructions() {
+        if let Some(callee) = inst.called_function() {
+            let callee = callee.name();
+            if UNSUPPORTED_FUNCTIONS
+                .iter()
+                .any(|u| callee == *u || callee.starts_with(&format!("{}.", u)))
+                || UNSUPPORTED_PREFIXES.iter().any(|p| callee.starts_with(p))
+            {
+                found.push((
+                    Lint::UnsupportedIntrinsic,
+                    name.clone(),
+                    format!("call to unsupported `{}`", callee),
+                ));
+            }
+        }
+        if inst.is_indirect_call() {
+            found.push((
+                Lint::FunctionPointer,
+                name.clone(),
+                "call through a function pointer".into(),
+            ));
+        }
+        let op = match inst.opcode() {
+            Opcode::Mul => Some("multiplication"),
+            Opcode::UDiv | Opcode::SDiv => Some("division"),
+            Opcode::URem | Opcode::SRem => Some("remainder"),
+            Opcode::Shl | Opcode::LShr | Opcode::AShr => Some("shift"),
+            _ => None,
+        };
+        if let (Some(op), Some(128)) = (op, inst.int_width()) {
+            found.push((Lint::I128, name.clone(), format!("i128 {}", op)));
+        }
+        for g in inst
+            .globals()
+            .iter()
+            .filter(|g| g.address_space() == 0 && !g.is_constant())
+        {
+            found.push((
+                Lint::GenericGlobal,
+                name.clone(),
+                format!("global `{}` in generic address space", g.name()),
+            ));
+        }
+    }
+    found
+}
+
+/// Cycles of direct calls reachable from the kernel
+fn recursion(kernel: &Function) -> Vec<(Lint, String, String)> {
+    fn visit(
+        f: Function,
+        path: &mut Vec<String>,
+        done: &mut BTreeSet<String>,
+        found: &mut Vec<(Lint, String, String)>,
+    ) {
+        let name = f.name();
+        if let Some(pos) = path.iter().position(|p| *p == name) {
+            let cycle: Vec<String> = path[pos..]
+                .iter()
+                .chain(Some(&name))
+                .map(|s| symbol::demangle(s))
+                .collect();
+            found.push((
+                Lint::Recursion,
+                name,
+                format!("recursive call {}", cycle.join(" -> ")),
+            ));
+            return;
+        }
+        if f.is_declaration() || done.contains(&name) {
+            return;
+        }
+        path.push(name.clone());
+        for callee in f.callees() {
+            visit(callee, path, done, found);
+        }
+        path.pop();
+        done.insert(name);
+    }
+    let mut found = Vec::new();
+    visit(*kernel, &mut Vec::new(), &mut BTreeSet::new(), &mut found);
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IR: &str = r#"
+target triple = "nvptx64-nvidia-cuda"
+
+@counter = global i32 0
+@message = constant [2 x i8] c"ok"
+
+define i32 @fib(i32 %n) {
+  %c = icmp ult i32 %n, 2
+  br i1 %c, label %done, label %rec
+rec:
+  %n1 = sub i32 %n, 1
+  %f1 = call i32 @fib(i32 %n1)
+  ret i32 %f1
+done:
+  ret i32 %n
+}
+
+define i128 @div(i128 %a, i128 %b) {
+  %q = udiv i128 %a, %b
+  ret i128 %q
+}
+
+declare void @abort()
+declare void @llvm.trap()
+declare void @llvm.x86.sse2.pause()
+
+define ptx_kernel void @kernel(i32 ()* %f) {
+  %x = call i32 @fib(i32 10)
+  %y = call i32 %f()
+  call void @llvm.trap()
+  call void @abort()
+  call void @llvm.x86.sse2.pause()
+  %z = call i128 @div(i128 1, i128 2)
+  store i32 %x, i32* @counter
+  %m = load i8, i8* getelementptr ([2 x i8], [2 x i8]* @message, i32 0, i32 0)
+  ret void
+}
+
+define ptx_kernel void @clean() {
+  ret void
+}
+"#;
+
+    #[test]
+    fn lints() {
+        let module = Module::parse_ir(IR).unwrap();
+        let mut levels = BTreeMap::new();
+        levels.insert("recursion".to_string(), Level::Deny);
+        let diags = check(&module, &levels).unwrap();
+        let found: Vec<_> = diags
+            .iter()
+            .map(|d| (d.lint, d.level, d.kernel.as_str(), d.function.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (Lint::FunctionPointer, Level::Warn, "kernel", "kernel"),
+                (Lint::UnsupportedIntrinsic, Level::Warn, "kernel", "kernel"),
+                (Lint::UnsupportedIntrinsic, Level::Warn, "kernel", "kernel"),
+                (Lint::GenericGlobal, Level::Warn, "kernel", "kernel"),
+                (Lint::I128, Level::Warn, "kernel", "div"),
+                (Lint::Recursion, Level::Deny, "kernel", "fib"),
+            ]
+        );
+        // `llvm.trap` is supported by NVPTX
+        assert_eq!(diags[1].message, "call to unsupported `abort`");
+        assert_eq!(
+            diags[2].message,
+            "call to unsupported `llvm.x86.sse2.pause`"
+        );
+        assert_eq!(
+            diags[5].to_string(),
+            "kernel `kernel`: recursive call fib -> fib in `fib` [recursion]"
+        );
+
+        levels.insert("function-pointer".to_string(), Level::Allow);
+        let diags = check(&module, &levels).unwrap();
+        assert!(diags.iter().all(|d| d.lint != Lint::FunctionPointer));
+        assert_eq!(diags.len(), 5);
+
+        levels.insert("unknown".to_string(), Level::Deny);
+        assert!(check(&module, &levels).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+//! Discovery of LLVM tools (`llvm-link`, `opt`, `llc`)
+//!
+//! Tools are resolved in the following order:
+//!
+//! 1. Explicit per-tool overrides (e.g. `Driver::set_llvm_tool`)
+//! 2. `bin/` of the LLVM root given by `NVPTX_LLVM_ROOT` (or [Discovery::set_root])
+//! 3. `llvm-config --bindir` of the `llvm-config` given by `LLVM_CONFIG` (or [Discovery::set_llvm_config])
+//! 4. `PATH`, using the version suffixes (e.g. `llvm-link-6.0`), and then the bare name
+//! 5. `llvm-config --bindir` of `llvm-config` found in `PATH` with the same suffixes
+//!
+//! All tools must come from the same LLVM major version. In 4. and 5., the first suffix
+//! (or `llvm-config`) providing all tools with the same major version is used.
+
+use failure::err_msg;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::path::*;
+use std::process;
+use std::str::from_utf8;
+
+use crate::error::ResultAny;
+
+/// Environment variable for the root directory of LLVM installation
+pub const LLVM_ROOT_ENV: &str = "NVPTX_LLVM_ROOT";
+/// Environment variable for the path of `llvm-config`
+pub const LLVM_CONFIG_ENV: &str = "LLVM_CONFIG";
+
+/// Default version suffixes searched in `PATH`
+const DEFAULT_SUFFIXES: [&str; 2] = ["6.0", "7.0"];
+
+/// LLVM tools used by the driver
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tool {
+    Link,
+    Opt,
+    Llc,
+}
+
+impl Tool {
+    pub fn all() -> &'static [Tool] {
+        &[Tool::Link, Tool::Opt, Tool::Llc]
+    }
+
+    /// Command name without version suffix
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::Link => "llvm-link",
+            Tool::Opt => "opt",
+            Tool::Llc => "llc",
+        }
+    }
+}
+
+/// Resolved LLVM tools
+#[derive(Debug, Clone)]
+pub struct Tools {
+    pub llvm_link: PathBuf,
+    pub opt: PathBuf,
+    pub llc: PathBuf,
+    /// LLVM major version shared by all tools
+    pub major: u32,
+}
+
+impl Tools {
+    pub fn get(&self, tool: Tool) -> &Path {
+        match tool {
+            Tool::Link => &self.llvm_link,
+            Tool::Opt => &self.opt,
+            Tool::Llc => &self.llc,
+        }
+    }
+}
+
+/// Setting for resolving LLVM tools
+#[derive(Debug, Clone)]
+pub struct Discovery {
+    root: Option<PathBuf>,
+    llvm_config: Option<PathBuf>,
+    suffixes: Vec<String>,
+    overrides: HashMap<Tool, PathBuf>,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Discovery {
+            root: env::var_os(LLVM_ROOT_ENV).map(PathBuf::from),
+            llvm_config: env::var_os(LLVM_CONFIG_ENV).map(PathBuf::from),
+            suffixes: DEFAULT_SUFFIXES.iter().map(|s| s.to_string()).collect(),
+            overrides: HashMap::new(),
+        }
+    }
+}
+
+impl Discovery {
+    pub fn set_root<P: AsRef<Path>>(&mut self, root: P) {
+        self.root = Some(root.as_ref().to_owned());
+    }
+
+    pub fn set_llvm_config<P: AsRef<Path>>(&mut self, llvm_config: P) {
+        self.llvm_config = Some(llvm_config.as_ref().to_owned());
+    }
+
+    /// Version suffixes searched in `PATH`, e.g. `["6.0", "7.0"]` for `llvm-link-6.0` and `llvm-link-7.0`
+    pub fn set_suffixes(&mut self, suffixes: &[&str]) {
+        self.suffixes = suffixes.iter().map(|s| s.to_string()).collect();
+    }
+
+    pub fn set_tool<P: AsRef<Path>>(&mut self, tool: Tool, path: P) {
+        self.overrides.insert(tool, path.as_ref().to_owned());
+    }
+
+    /// Resolve all tools, and check their versions are consistent
+    pub fn resolve(&self) -> ResultAny<Tools> {
+        let sets = match self.explicit_bindir()? {
+            Some(bindir) => vec![Tool::all().iter().map(|t| bindir.join(t.name())).collect()],
+            None => self.candidate_sets(),
+        };
+        self.select(&sets)
+    }
+
+    /// `bin/` directory specified by the root or `llvm-config`
+    fn explicit_bindir(&self) -> ResultAny<Option<PathBuf>> {
+        if let Some(root) = &self.root {
+            return Ok(Some(root.join("bin")));
+        }
+        if let Some(config) = &self.llvm_config {
+            return match llvm_config_bindir(config) {
+                Some(bindir) => Ok(Some(bindir)),
+                None => Err(err_msg(format!(
+                    "Cannot get --bindir from {}",
+                    config.display()
+                ))),
+            };
+        }
+        Ok(None)
+    }
+
+    /// Sets of tool paths in the order of `Tool::all()`, for each suffix and `llvm-config`
+    fn candidate_sets(&self) -> Vec<Vec<PathBuf>> {
+        let mut sets: Vec<Vec<PathBuf>> = (0..=self.suffixes.len())
+            .map(|i| {
+                Tool::all()
+                    .iter()
+                    .map(|t| self.suffixed(t.name())[i].clone())
+                    .collect()
+            })
+            .collect();
+        for config in self.suffixed("llvm-config") {
+            if let Some(bindir) = llvm_config_bindir(&config) {
+                sets.push(Tool::all().iter().map(|t| bindir.join(t.name())).collect());
+            }
+        }
+        sets
+    }
+
+    /// The first set where all tools (or overrides) exist with the same major version
+    fn select(&self, sets: &[Vec<PathBuf>]) -> ResultAny<Tools> {
+        let mut fixed = HashMap::new();
+        for (&tool, path) in &self.overrides {
+            match version(path) {
+                Some(v) => fixed.insert(tool, (path.clone(), v)),
+                None => {
+                    return Err(err_msg(format!(
+                        "LLVM command {} (specified for {}) is not executable",
+                        path.display(),
+                        tool.name()
+                    )))
+                }
+            };
+        }
+        let mut versions: HashMap<PathBuf, Option<u32>> = HashMap::new();
+        let mut tried = Vec::new();
+        let mut existing = HashSet::new();
+        let mut inconsistent = None;
+        for set in sets {
+            let mut found = fixed.clone();
+            for (&tool, path) in Tool::all().iter().zip(set) {
+                if found.contains_key(&tool) {
+                    continue;
+                }
+                let v = *versions
+                    .entry(path.clone())
+                    .or_insert_with(|| version(path));
+                match v {
+                    Some(v) => {
+                        existing.insert(tool);
+                        found.insert(tool, (path.clone(), v));
+                    }
+                    None => tried.push(path.display().to_string()),
+                }
+            }
+            if found.len() < Tool::all().len() {
+                continue;
+            }
+            let major = found[&Tool::Link].1;
+            if found.values().all(|(_, v)| *v == major) {
+                let mut take = |tool| found.remove(&tool).unwrap().0;
+                return Ok(Tools {
+                    llvm_link: take(Tool::Link),
+                    opt: take(Tool::Opt),
+                    llc: take(Tool::Llc),
+                    major,
+                });
+            }
+            inconsistent.get_or_insert(found);
+        }
+        if let Some(found) = inconsistent {
+            let versions: Vec<_> = Tool::all()
+                .iter()
+                .map(|tool| {
+                    let (path, v) = &found[tool];
+                    format!("{} (LLVM {})", path.display(), v)
+                })
+                .collect();
+            return Err(err_msg(format!(
+                "LLVM tools come from different major versions: {}",
+                versions.join(", ")
+            )));
+        }
+        match Tool::all()
+            .iter()
+            .find(|tool| !existing.contains(tool) && !fixed.contains_key(tool))
+        {
+            Some(tool) => Err(err_msg(format!(
+                "LLVM command {} is not found (tried: {})",
+                tool.name(),
+                tried.join(", ")
+            ))),
+            None => Err(err_msg(format!(
+                "LLVM tools with the same suffix are not found (tried: {})",
+                tried.join(", ")
+            ))),
+        }
+    }
+
+    /// `name-{suffix}` for all suffixes, and then bare `name`
+    fn suffixed(&self, name: &str) -> Vec<PathBuf> {
+        self.suffixes
+            .iter()
+            .map(|s| PathBuf::from(format!("{}-{}", name, s)))
+            .chain(Some(PathBuf::from(name)))
+            .collect()
+    }
+}
+
+fn llvm_config_bindir(config: &Path) -> Option<PathBuf> {
+    let output = process::Command::new(config)
+        .arg("--bindir")
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let bindir = from_utf8(&output.stdout).ok()?.trim();
+    Some(PathBuf::from(bindir))
+}
+
+/// LLVM major version of the command, `None` if it cannot be executed
+fn version(command: &Path) -> Option<u32> {
+    let output = process::Command::new(command)
+        .arg("--version")
+        .stdin(process::Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_major_version(from_utf8(&output.stdout).ok()?)
+}
+
+/// Parse `--version` output of LLVM tools, e.g.
+///
+/// ```text
+/// LLVM (http://llvm.org/):
+///   LLVM version 6.0.0
+/// ```
+fn parse_major_version(output: &str) -> Option<u32> {
+    let line = output.lines().find(|line| line.contains("LLVM version"))?;
+    let version = line.split("LLVM version").nth(1)?.trim();
+    version.split('.').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_version() {
+        let v6 = "LLVM (http://llvm.org/):\n  LLVM version 6.0.0\n  Optimized build.\n";
+        assert_eq!(parse_major_version(v6), Some(6));
+        let v14 = "Ubuntu LLVM version 14.0.6\n  Optimized build.\n";
+        assert_eq!(parse_major_version(v14), Some(14));
+        assert_eq!(parse_major_version("opt: unknown"), None);
+    }
+
+    #[test]
+    fn suffixed_names() {
+        let mut disc = Discovery::default();
+        disc.set_suffixes(&["8"]);
+        assert_eq!(
+            disc.suffixed("llc"),
+            vec![PathBuf::from("llc-8"), PathBuf::from("llc")]
+        );
+    }
+
+    #[test]
+    fn consistent_suffix() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempdir::TempDir::new("nvptx-llvm").unwrap();
+        let fake = |name: &str, major: u32| {
+            let path = dir.path().join(name);
+            let script = format!("#!/bin/sh\necho \"LLVM version {}.0.0\"\n", major);
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+        // llvm-link is only available for 6.0
+        let set = |suffix: &str| -> Vec<PathBuf> {
+            Tool::all()
+                .iter()
+                .map(|t| dir.path().join(format!("{}-{}", t.name(), suffix)))
+                .collect()
+        };
+        fake("llvm-link-6.0", 6);
+        fake("opt-6.0", 6);
+        fake("llc-6.0", 6);
+        fake("opt-7.0", 7);
+        fake("llc-7.0", 7);
+        let mut disc = Discovery::default();
+        let tools = disc.select(&[set("7.0"), set("6.0")]).unwrap();
+        assert_eq!(tools.major, 6);
+        assert_eq!(tools.opt, dir.path().join("opt-6.0"));
+
+        disc.set_tool(Tool::Link, fake("llvm-link-7.0", 7));
+        let tools = disc.select(&[set("6.0"), set("7.0")]).unwrap();
+        assert_eq!(tools.major, 7);
+        disc.set_tool(Tool::Llc, dir.path().join("llc-6.0"));
+        assert!(disc.select(&[set("7.0")]).is_err());
+    }
+}
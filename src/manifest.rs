@@ -1,16 +1,47 @@
+//! Cargo.toml of kernel crates
+//!
+//! [CargoTOML] can be loaded from an existing Cargo.toml, modified, and saved again.
+//! Keys which are not modeled here (e.g. `[features]`) are kept as is.
+//!
+//! Cargo.toml generated by the driver starts with [GENERATED_MARKER].
+//! [write] does not overwrite Cargo.toml without the marker unless [WriteMode::Force] is used,
+//! and [WriteMode::Merge] updates only the dependencies and nvptx metadata
+//! while keeping the formatting and comments of the existing Cargo.toml.
+
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fs;
 use std::path::*;
-use toml;
+use toml::{self, Value};
+use toml_edit::{self, Document, Item, Table};
 
 use super::save_str;
 use crate::error::*;
+use crate::lint::Level;
 
-#[derive(Debug, Clone)]
+/// Dependency crate
+#[derive(Debug, Clone, PartialEq)]
 pub struct Crate {
     pub name: String,
     pub version: Option<String>,
     pub path: Option<PathBuf>,
+    pub git: Option<GitSource>,
+    pub features: Vec<String>,
+    pub default_features: bool,
+}
+
+/// Git repository of a dependency
+#[derive(Debug, Clone, PartialEq)]
+pub struct GitSource {
+    pub url: String,
+    pub reference: Option<GitReference>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GitReference {
+    Rev(String),
+    Branch(String),
+    Tag(String),
 }
 
 impl Crate {
@@ -19,69 +50,373 @@ impl Crate {
             name: name.into(),
             version: None,
             path: None,
+            git: None,
+            features: Vec::new(),
+            default_features: true,
         }
     }
 
     pub fn new(name: &str, version: &str) -> Self {
         Self {
-            name: name.into(),
             version: Some(version.into()),
-            path: None,
+            ..Self::latest(name)
         }
     }
 
     pub fn with_path<P: AsRef<Path>>(name: &str, path: P) -> Self {
         Self {
-            name: name.into(),
-            version: None,
             path: Some(path.as_ref().into()),
+            ..Self::latest(name)
+        }
+    }
+
+    pub fn with_git(name: &str, url: &str, reference: Option<GitReference>) -> Self {
+        Self {
+            git: Some(GitSource {
+                url: url.into(),
+                reference,
+            }),
+            ..Self::latest(name)
+        }
+    }
+
+    /// Entry in `[dependencies]`
+    pub fn dependency(&self) -> Dependency {
+        if self.path.is_none()
+            && self.git.is_none()
+            && self.features.is_empty()
+            && self.default_features
+        {
+            return Dependency::Simple(self.version.clone().unwrap_or_else(|| "*".into()));
+        }
+        let mut detail = DependencyDetail {
+            version: self.version.clone(),
+            path: self.path.as_ref().map(|p| p.to_str().unwrap().into()),
+            features: self.features.clone(),
+            default_features: if self.default_features {
+                None
+            } else {
+                Some(false)
+            },
+            ..Default::default()
+        };
+        if let Some(git) = &self.git {
+            detail.git = Some(git.url.clone());
+            match &git.reference {
+                Some(GitReference::Rev(rev)) => detail.rev = Some(rev.clone()),
+                Some(GitReference::Branch(branch)) => detail.branch = Some(branch.clone()),
+                Some(GitReference::Tag(tag)) => detail.tag = Some(tag.clone()),
+                None => {}
+            }
+        }
+        Dependency::Detailed(detail)
+    }
+
+    /// Crate from an entry in `[dependencies]`
+    pub fn from_dependency(name: &str, dep: &Dependency) -> Self {
+        let detail = match dep {
+            Dependency::Simple(version) => return Self::new(name, version),
+            Dependency::Detailed(detail) => detail,
+        };
+        let reference = if let Some(rev) = &detail.rev {
+            Some(GitReference::Rev(rev.clone()))
+        } else if let Some(branch) = &detail.branch {
+            Some(GitReference::Branch(branch.clone()))
+        } else {
+            detail.tag.clone().map(GitReference::Tag)
+        };
+        Self {
+            name: name.into(),
+            version: detail.version.clone(),
+            path: detail.path.as_ref().map(PathBuf::from),
+            git: detail.git.as_ref().map(|url| GitSource {
+                url: url.clone(),
+                reference,
+            }),
+            features: detail.features.clone(),
+            default_features: detail.default_features.unwrap_or(true),
+        }
+    }
+}
+
+/// First line of Cargo.toml generated by the driver
+pub const GENERATED_MARKER: &str =
+    "# Generated by nvptx. Remove this line to keep this file from being overwritten.";
+
+/// How to write Cargo.toml into a directory which may already have one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMode {
+    /// Create new Cargo.toml. Existing one is overwritten only if it is generated by the driver.
+    #[default]
+    Generate,
+    /// Add or update the dependencies and nvptx metadata in existing Cargo.toml
+    Merge,
+    /// Overwrite existing Cargo.toml
+    Force,
+}
+
+/// Write Cargo.toml with the dependencies and runtime setting
+pub fn write<P: AsRef<Path>>(
+    path: P,
+    crates: &[Crate],
+    runtime: &[String],
+    mode: WriteMode,
+) -> Result<()> {
+    let path = path.as_ref();
+    let exists = path.join("Cargo.toml").exists();
+    match mode {
+        WriteMode::Merge if exists => {
+            merge(path, crates, runtime).log(Step::Ready, "Failed to update Cargo.toml")
+        }
+        WriteMode::Generate if exists && !is_generated(path) => Err(err_msg(
+            Step::Ready,
+            &format!(
+                "{} is not generated by nvptx. Use merge or force mode to update it.",
+                path.join("Cargo.toml").display()
+            ),
+        )),
+        _ => {
+            let mut setting = CargoTOML::from_crates(crates);
+            if !runtime.is_empty() {
+                setting.set_runtime(runtime);
+            }
+            setting.save(path)
+        }
+    }
+}
+
+/// Check Cargo.toml in the directory is generated by the driver
+pub fn is_generated<P: AsRef<Path>>(path: P) -> bool {
+    match fs::read_to_string(path.as_ref().join("Cargo.toml")) {
+        Ok(toml) => toml.lines().next() == Some(GENERATED_MARKER),
+        Err(_) => false,
+    }
+}
+
+fn merge(path: &Path, crates: &[Crate], runtime: &[String]) -> ResultAny<()> {
+    let manifest = path.join("Cargo.toml");
+    let mut doc: Document = fs::read_to_string(&manifest)?.parse()?;
+    let deps = child_table(doc.as_table_mut(), "dependencies", false)?;
+    for c in crates {
+        let new = edit_value(&Value::try_from(c.dependency())?);
+        match deps.get_mut(&c.name) {
+            // `[dependencies.foo]` table or inline table: update keys to keep the others and comments
+            Some(item) if item.is_table_like() => {
+                let old = item.as_table_like_mut().unwrap();
+                let new = match new {
+                    toml_edit::Value::InlineTable(table) => table,
+                    version => std::iter::once(("version", version)).collect(),
+                };
+                for key in &SOURCE_KEYS {
+                    if !new.contains_key(key) {
+                        old.remove(key);
+                    }
+                }
+                for (key, value) in new.iter() {
+                    match old.get_mut(key).and_then(|item| item.as_value_mut()) {
+                        Some(old) => replace_value(old, value.clone()),
+                        None => {
+                            old.insert(key, Item::Value(value.clone()));
+                        }
+                    }
+                }
+            }
+            Some(item) => match item.as_value_mut() {
+                Some(old) => replace_value(old, new),
+                None => *item = Item::Value(new),
+            },
+            None => {
+                deps.insert(&c.name, Item::Value(new));
+            }
         }
     }
+    if !runtime.is_empty() {
+        let package = child_table(doc.as_table_mut(), "package", false)?;
+        let metadata = child_table(package, "metadata", true)?;
+        let nvptx = child_table(metadata, "nvptx", false)?;
+        let runtime: toml_edit::Array = runtime.iter().collect();
+        nvptx.insert("runtime", toml_edit::value(runtime));
+    }
+    fs::write(&manifest, doc.to_string())?;
+    Ok(())
+}
+
+/// Keys of a dependency given by [Crate], other keys (e.g. `optional`) are kept in merge
+const SOURCE_KEYS: [&str; 8] = [
+    "version",
+    "path",
+    "git",
+    "rev",
+    "branch",
+    "tag",
+    "default-features",
+    "features",
+];
+
+/// Replace the value keeping comments around the old one
+fn replace_value(old: &mut toml_edit::Value, new: toml_edit::Value) {
+    let decor = old.decor().clone();
+    *old = new;
+    *old.decor_mut() = decor;
+}
+
+/// Table in the parent table, created if not exists
+fn child_table<'a>(parent: &'a mut Table, key: &str, implicit: bool) -> ResultAny<&'a mut Table> {
+    parent
+        .entry(key)
+        .or_insert_with(|| {
+            let mut table = Table::new();
+            table.set_implicit(implicit);
+            Item::Table(table)
+        })
+        .as_table_mut()
+        .ok_or_else(|| failure::err_msg(format!("`{}` in Cargo.toml must be a table", key)))
+}
+
+fn edit_value(value: &Value) -> toml_edit::Value {
+    match value {
+        Value::String(s) => s.as_str().into(),
+        Value::Integer(i) => (*i).into(),
+        Value::Float(f) => (*f).into(),
+        Value::Boolean(b) => (*b).into(),
+        Value::Datetime(d) => d.to_string().into(),
+        Value::Array(array) => array
+            .iter()
+            .map(edit_value)
+            .collect::<toml_edit::Array>()
+            .into(),
+        Value::Table(table) => table
+            .iter()
+            .map(|(key, value)| (key.as_str(), edit_value(value)))
+            .collect::<toml_edit::InlineTable>()
+            .into(),
+    }
 }
 
 /// Generate Cargo.toml
 pub fn generate<P: AsRef<Path>>(path: P, crates: &[Crate]) -> Result<()> {
-    let setting = CargoTOML::from_crates(&crates);
-    save_str(&path, &setting.as_toml(), "Cargo.toml")
-        .log(Step::Ready, "Failed to write Cargo.toml")?;
-    Ok(())
+    write(path, crates, &[], WriteMode::Generate)
+}
+
+/// Generate Cargo.toml with runtime setting
+///
+/// ```text
+/// [package.metadata.nvptx]
+/// runtime = ["core"]
+/// ```
+pub fn generate_with_runtime<P: AsRef<Path>>(
+    path: P,
+    crates: &[Crate],
+    runtime: &[String],
+) -> Result<()> {
+    write(path, crates, runtime, WriteMode::Generate)
 }
 
-#[derive(Serialize)]
-struct CargoTOML {
-    package: Package,
-    profile: Profile,
-    dependencies: Dependencies,
+/// Content of Cargo.toml
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CargoTOML {
+    pub package: Package,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lib: Option<Lib>,
+    #[serde(default, skip_serializing_if = "Profiles::is_empty")]
+    pub profile: Profiles,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, Dependency>,
+    /// `[patch.{source}]` tables, e.g. `[patch.crates-io]`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub patch: BTreeMap<String, BTreeMap<String, Dependency>>,
+    /// Other keys, kept as is
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
 }
 
 impl CargoTOML {
+    /// Manifest of a scratch crate depending on the crates
     pub fn from_crates(crates: &[Crate]) -> Self {
-        let dependencies = crates
-            .iter()
-            .cloned()
-            .map(|c| {
-                let name = c.name;
-                let version = c.version.unwrap_or("*".to_string());
-                let path = c.path.map(|p| p.to_str().unwrap().into());
-                (name, CrateInfo { version, path })
-            })
-            .collect();
-        CargoTOML {
+        let mut setting = CargoTOML {
             package: Package::default(),
-            profile: Profile::default(),
-            dependencies,
+            lib: None,
+            profile: Profiles {
+                dev: Some(Profile {
+                    debug: Some(DebugInfo::Bool(false)),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            dependencies: BTreeMap::new(),
+            patch: BTreeMap::new(),
+            extra: BTreeMap::new(),
+        };
+        for c in crates {
+            setting.add_crate(c);
         }
+        setting
+    }
+
+    /// Load Cargo.toml in the directory
+    pub fn load<P: AsRef<Path>>(path: P) -> ResultAny<Self> {
+        let toml = fs::read_to_string(path.as_ref().join("Cargo.toml"))?;
+        Self::from_toml(&toml)
+    }
+
+    pub fn from_toml(toml: &str) -> ResultAny<Self> {
+        Ok(toml::from_str(toml)?)
     }
 
     pub fn as_toml(&self) -> String {
-        toml::to_string(&self).unwrap()
+        // Convert into `Value` first to place tables after values
+        let value = Value::try_from(self).unwrap();
+        toml::to_string(&value).unwrap()
+    }
+
+    /// Save as Cargo.toml in the directory, marked as generated by the driver
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let toml = format!("{}\n{}", GENERATED_MARKER, self.as_toml());
+        save_str(&path, &toml, "Cargo.toml").log(Step::Ready, "Failed to write Cargo.toml")?;
+        Ok(())
+    }
+
+    /// Add or replace a dependency
+    pub fn add_crate(&mut self, c: &Crate) {
+        self.dependencies.insert(c.name.clone(), c.dependency());
+    }
+
+    pub fn crates(&self) -> Vec<Crate> {
+        self.dependencies
+            .iter()
+            .map(|(name, dep)| Crate::from_dependency(name, dep))
+            .collect()
+    }
+
+    /// `runtime` in `[package.metadata.nvptx]`
+    pub fn runtime(&self) -> Vec<String> {
+        self.package
+            .metadata
+            .as_ref()
+            .and_then(|meta| meta.nvptx.as_ref())
+            .map(|nvptx| nvptx.runtime.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn set_runtime(&mut self, runtime: &[String]) {
+        let meta = self.package.metadata.get_or_insert_with(Default::default);
+        let nvptx = meta.nvptx.get_or_insert_with(Default::default);
+        nvptx.runtime = runtime.to_vec();
     }
 }
 
-#[derive(Serialize)]
-struct Package {
-    name: String,
-    version: String,
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edition: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
 }
 
 impl Default for Package {
@@ -89,38 +424,351 @@ impl Default for Package {
         Package {
             name: "accel-nvptx-builder".to_string(),
             version: "0.1.0".to_string(),
+            authors: Vec::new(),
+            edition: None,
+            metadata: None,
+            extra: BTreeMap::new(),
         }
     }
 }
 
-#[derive(Serialize)]
-struct Profile {
-    dev: DevProfile,
+/// `[package.metadata]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nvptx: Option<NvptxMetadata>,
+    /// Metadata for other tools
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
 }
 
-impl Default for Profile {
-    fn default() -> Self {
-        Profile {
-            dev: DevProfile::default(),
+/// `[package.metadata.nvptx]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NvptxMetadata {
+    /// Runtime crates linked as bitcode, e.g. `["core"]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub runtime: Vec<String>,
+    /// Launch bounds of kernels, `[package.metadata.nvptx.kernels.<name>]`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub kernels: BTreeMap<String, LaunchBounds>,
+    /// Names of kernels in PTX, `[package.metadata.nvptx.export]`,
+    /// keyed by the symbol or its demangled path, e.g. `"kernel::add" = "add"`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub export: BTreeMap<String, String>,
+    /// Glob patterns of kernels exported in PTX, e.g. `["add*"]`. All kernels are exported if empty.
+    #[serde(
+        default,
+        rename = "include-kernels",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub include_kernels: Vec<String>,
+    /// Glob patterns of kernels dropped from PTX, e.g. `["accel_core::*"]`
+    #[serde(
+        default,
+        rename = "exclude-kernels",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub exclude_kernels: Vec<String>,
+    /// Levels of lints, `[package.metadata.nvptx.lints]`, e.g. `recursion = "deny"`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub lints: BTreeMap<String, Level>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+/// Launch bounds of a kernel emitted as `.maxntid`, `.reqntid`, `.minnctapersm` and `.maxnreg`
+///
+/// ```toml
+/// [package.metadata.nvptx.kernels.add]
+/// maxntid = [256]
+/// minctasm = 2
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LaunchBounds {
+    /// Maximum number of threads in a block, up to 3 dimensions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maxntid: Option<Vec<u32>>,
+    /// Required number of threads in a block, up to 3 dimensions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqntid: Option<Vec<u32>>,
+    /// Minimum number of blocks per multiprocessor
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minctasm: Option<u32>,
+    /// Maximum number of registers per thread
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maxnreg: Option<u32>,
+}
+
+impl LaunchBounds {
+    /// Key and value pairs of `!nvvm.annotations`, e.g. `[("maxntidx", 256)]`
+    pub fn annotations(&self) -> ResultAny<Vec<(String, u32)>> {
+        let mut annotations = Vec::new();
+        for (key, dims) in &[("maxntid", &self.maxntid), ("reqntid", &self.reqntid)] {
+            let dims = match dims {
+                Some(dims) => dims,
+                None => continue,
+            };
+            if dims.is_empty() || dims.len() > 3 || dims.contains(&0) {
+                return Err(failure::err_msg(format!(
+                    "{} must be 1 to 3 positive numbers: {:?}",
+                    key, dims
+                )));
+            }
+            for (dim, n) in ["x", "y", "z"].iter().zip(dims) {
+                annotations.push((format!("{}{}", key, dim), *n));
+            }
+        }
+        if let Some(n) = self.minctasm {
+            annotations.push(("minctasm".into(), n));
+        }
+        if let Some(n) = self.maxnreg {
+            annotations.push(("maxnreg".into(), n));
         }
+        Ok(annotations)
     }
 }
 
-#[derive(Serialize)]
-struct DevProfile {
-    debug: bool,
+/// `[lib]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Lib {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub crate_type: Vec<String>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
 }
 
-impl Default for DevProfile {
-    fn default() -> Self {
-        DevProfile { debug: false }
+/// `[profile.*]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Profiles {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dev: Option<Profile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release: Option<Profile>,
+    /// Other profiles, e.g. `[profile.test]`
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl Profiles {
+    pub fn is_empty(&self) -> bool {
+        self.dev.is_none() && self.release.is_none() && self.extra.is_empty()
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct CrateInfo {
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Profile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opt_level: Option<OptLevel>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debug: Option<DebugInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lto: Option<Lto>,
+    /// `"unwind"` or `"abort"`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub panic: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codegen_units: Option<u32>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+/// `opt-level = 3` or `opt-level = "s"`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OptLevel {
+    Level(u8),
+    Size(String),
+}
+
+/// `debug = true` or `debug = 2`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DebugInfo {
+    Bool(bool),
+    Level(u8),
+}
+
+/// `lto = true` or `lto = "thin"`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Lto {
+    Bool(bool),
+    Mode(String),
+}
+
+/// Entry in `[dependencies]`, `name = "version"` or `name = { ... }`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Dependency {
+    Simple(String),
+    Detailed(DependencyDetail),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DependencyDetail {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub path: Option<String>,
-    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_features: Option<bool>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
 }
 
-type Dependencies = HashMap<String, CrateInfo>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let toml = r#"
+[package]
+name = "kernel"
+version = "0.2.0"
+authors = ["nvptx developers"]
+edition = "2018"
+
+[package.metadata.nvptx]
+runtime = ["core", "alloc"]
+exclude-kernels = ["accel_core::*"]
+
+[package.metadata.nvptx.kernels.add]
+maxntid = [256, 2]
+minctasm = 2
+
+[package.metadata.nvptx.export]
+"kernel::add" = "add"
+
+[package.metadata.nvptx.lints]
+recursion = "deny"
+
+[lib]
+crate-type = ["rlib"]
+
+[profile.dev]
+debug = false
+panic = "abort"
+
+[profile.release]
+opt-level = "s"
+lto = "thin"
+codegen-units = 1
+
+[dependencies]
+accel-core = "0.2"
+nvptx-panic = { path = "../nvptx-panic" }
+num-traits = { version = "0.2", default-features = false, features = ["libm"] }
+accel = { git = "https://github.com/rust-accel/accel", branch = "master" }
+
+[patch.crates-io]
+accel-core = { path = "../accel-core" }
+
+[features]
+default = []
+"#;
+        let setting = CargoTOML::from_toml(toml).unwrap();
+        assert_eq!(setting.package.edition, Some("2018".into()));
+        assert_eq!(setting.runtime(), vec!["core", "alloc"]);
+        let nvptx = setting.package.metadata.as_ref().unwrap().nvptx.as_ref();
+        assert_eq!(nvptx.unwrap().export["kernel::add"], "add");
+        assert_eq!(nvptx.unwrap().exclude_kernels, vec!["accel_core::*"]);
+        assert_eq!(nvptx.unwrap().lints["recursion"], Level::Deny);
+        assert_eq!(
+            nvptx.unwrap().kernels["add"].annotations().unwrap(),
+            vec![
+                ("maxntidx".to_string(), 256),
+                ("maxntidy".to_string(), 2),
+                ("minctasm".to_string(), 2)
+            ]
+        );
+        assert_eq!(setting.lib.as_ref().unwrap().crate_type, vec!["rlib"]);
+        let release = setting.profile.release.as_ref().unwrap();
+        assert_eq!(release.opt_level, Some(OptLevel::Size("s".into())));
+        assert_eq!(release.codegen_units, Some(1));
+        assert!(setting.extra.contains_key("features"));
+
+        let crates = setting.crates();
+        let accel = crates.iter().find(|c| c.name == "accel").unwrap();
+        assert_eq!(
+            accel.git.as_ref().unwrap().reference,
+            Some(GitReference::Branch("master".into()))
+        );
+        let num = crates.iter().find(|c| c.name == "num-traits").unwrap();
+        assert!(!num.default_features);
+        assert_eq!(num.features, vec!["libm"]);
+
+        let setting2 = CargoTOML::from_toml(&setting.as_toml()).unwrap();
+        assert_eq!(setting, setting2);
+    }
+
+    #[test]
+    fn write_mode() {
+        let dir = tempdir::TempDir::new("nvptx-manifest").unwrap();
+        let user = r#"[package]
+name = "kernel" # user's crate
+version = "0.1.0"
+
+[dependencies]
+# GPU runtime
+accel-core = "0.1" # old
+
+[dependencies.num-traits] # math
+default-features = false
+version = "0.1" # pinned
+optional = true
+"#;
+        fs::write(dir.path().join("Cargo.toml"), user).unwrap();
+        let crates = [
+            Crate::new("accel-core", "0.2"),
+            Crate::new("num-traits", "0.2"),
+            Crate::with_path("nvptx-panic", "../nvptx-panic"),
+        ];
+        let runtime = ["core".to_string()];
+        assert!(write(dir.path(), &crates, &runtime, WriteMode::Generate).is_err());
+
+        write(dir.path(), &crates, &runtime, WriteMode::Merge).unwrap();
+        let merged = fs::read_to_string(dir.path().join("Cargo.toml")).unwrap();
+        assert!(merged.contains(r#"name = "kernel" # user's crate"#));
+        assert!(merged.contains("# GPU runtime\naccel-core = \"0.2\" # old\n"));
+        assert!(merged.contains(r#"nvptx-panic = { path = "../nvptx-panic" }"#));
+        assert!(merged.contains(
+            "[dependencies.num-traits] # math\nversion = \"0.2\" # pinned\noptional = true\n"
+        ));
+        assert!(merged.contains("[package.metadata.nvptx]\nruntime = [\"core\"]\n"));
+        assert!(!is_generated(dir.path()));
+
+        write(dir.path(), &crates, &runtime, WriteMode::Force).unwrap();
+        assert!(is_generated(dir.path()));
+        write(dir.path(), &crates, &runtime, WriteMode::Generate).unwrap();
+        let setting = CargoTOML::load(dir.path()).unwrap();
+        assert_eq!(setting.crates(), crates.to_vec());
+    }
+
+    #[test]
+    fn path_without_version() {
+        let setting = CargoTOML::from_crates(&[
+            Crate::latest("accel-core"),
+            Crate::with_path("nvptx-panic", "../nvptx-panic"),
+        ]);
+        let toml = setting.as_toml();
+        assert!(toml.contains(r#"accel-core = "*""#));
+        assert!(toml.contains("[dependencies.nvptx-panic]\npath = \"../nvptx-panic\"\n"));
+    }
+}
@@ -0,0 +1,47 @@
+//! Artifacts generated by the driver
+
+use std::path::*;
+
+use crate::llvm::Tools;
+use crate::target::Target;
+use crate::timings::Timings;
+
+/// Result of `Driver::compile`
+///
+/// Artifacts are placed in `target_dir`:
+///
+/// ```text
+/// target/nvptx64-nvidia-cuda/debug/
+/// ├── kernel.bc      # bitcode
+/// ├── kernel.opt.bc  # opt_bitcode
+/// ├── kernel.ptx     # ptx_path
+/// └── kernel.cubin   # cubin
+/// ```
+#[derive(Debug, Clone)]
+pub struct BuildOutput {
+    /// Directory containing the artifacts
+    pub target_dir: PathBuf,
+    /// Linked LLVM bitcode of the crate and runtimes
+    pub bitcode: PathBuf,
+    /// LLVM bitcode after dropping unused symbols
+    pub opt_bitcode: PathBuf,
+    /// Generated PTX file
+    pub ptx_path: PathBuf,
+    /// Content of the PTX file
+    pub ptx: String,
+    /// cubin file if converted by `Driver::cubin`
+    pub cubin: Option<PathBuf>,
+    /// Kernel functions (`extern "ptx-kernel"`) in the PTX
+    pub kernels: Vec<String>,
+    /// Demangled paths of `kernels` in the same order, e.g. `kernel::add`
+    pub demangled_kernels: Vec<String>,
+    /// Target GPU architecture, e.g. `sm_50`
+    pub arch: String,
+    pub target: Target,
+    /// rustup toolchain used for `cargo build`
+    pub toolchain: String,
+    /// LLVM tools used for linking and code generation
+    pub llvm: Tools,
+    pub release: bool,
+    pub timings: Timings,
+}
@@ -0,0 +1,1245 @@
+//! PTX assembly parser and printer
+//!
+//! ```
+//! use nvptx::ptx::Module;
+//!
+//! let ptx = Module::parse(r#"
+//! .version 6.0
+//! .target sm_50
+//! .address_size 64
+//!
+//! .visible .entry add(
+//!     .param .u64 add_param_0
+//! )
+//! {
+//!     .reg .b64 %rd<2>;
+//!     ld.param.u64 %rd1, [add_param_0];
+//!     ret;
+//! }
+//! "#).unwrap();
+//! assert_eq!(ptx.version().unwrap().to_string(), "6.0");
+//! assert_eq!(ptx.kernels().next().unwrap().name, "add");
+//! // Printed PTX can be parsed again into the same AST
+//! assert_eq!(Module::parse(&ptx.to_string()).unwrap(), ptx);
+//! ```
+//!
+//! Comments are dropped, and directives which are not modeled here
+//! (e.g. `.file`, `.loc`, `.pragma`) are kept as raw text.
+
+use failure::err_msg;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::ResultAny;
+
+/// PTX module, i.e. content of a `.ptx` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub directives: Vec<Directive>,
+}
+
+/// Module-level directive
+#[derive(Debug, Clone, PartialEq)]
+pub enum Directive {
+    /// `.version 6.0`
+    Version(Version),
+    /// `.target sm_50`, possibly with options like `debug`
+    Target(Vec<String>),
+    /// `.address_size 64`
+    AddressSize(u32),
+    Variable(Variable),
+    Function(Function),
+    /// Other directive as raw text
+    Other(String),
+}
+
+/// PTX ISA version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionKind {
+    /// `.entry`, i.e. kernel
+    Entry,
+    /// `.func`, device function
+    Func,
+}
+
+/// `.entry` or `.func`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    /// `visible`, `extern` or `weak`
+    pub linkage: Option<String>,
+    pub kind: FunctionKind,
+    pub name: String,
+    /// Return parameters of `.func`
+    pub returns: Vec<Variable>,
+    pub params: Vec<Variable>,
+    /// Performance-tuning directives, e.g. `.maxntid 256, 1, 1`
+    pub performance: Vec<PerformanceDirective>,
+    /// `None` for declaration
+    pub body: Option<Vec<Statement>>,
+}
+
+/// Performance-tuning directive of a function, e.g. `.maxntid 256, 1, 1`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PerformanceDirective {
+    /// Name without the leading dot, e.g. `maxntid`
+    pub name: String,
+    pub values: Vec<u32>,
+}
+
+/// Statement in a function body
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Label(String),
+    Variable(Variable),
+    Instruction(Instruction),
+    /// Nested scope `{ ... }`
+    Block(Vec<Statement>),
+    /// Other directive as raw text
+    Other(String),
+}
+
+/// Variable declaration, e.g. `.shared .align 4 .b8 cache[1024]`, `.reg .b32 %r<7>`,
+/// or parameter of a function
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    /// `visible`, `extern` or `weak`
+    pub linkage: Option<String>,
+    pub space: StateSpace,
+    pub align: Option<u32>,
+    /// `.v2` or `.v4`
+    pub vector: Option<u32>,
+    /// Type without the leading dot, e.g. `u64`
+    pub ty: String,
+    /// `.ptr` attributes of kernel parameters
+    pub ptr: Option<PtrAttribute>,
+    pub name: String,
+    /// Number of parameterized registers, e.g. `7` of `%r<7>`
+    pub count: Option<u32>,
+    /// Array dimensions, `None` for unsized `[]`
+    pub dims: Vec<Option<u32>>,
+    /// Initializer as raw text, e.g. `{1, 2, 3}`
+    pub init: Option<String>,
+}
+
+/// State space of variables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateSpace {
+    Reg,
+    Sreg,
+    Const,
+    Global,
+    Local,
+    Param,
+    Shared,
+    Tex,
+}
+
+/// `.ptr .global .align 8` of kernel parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct PtrAttribute {
+    pub space: Option<StateSpace>,
+    pub align: Option<u32>,
+}
+
+/// Instruction, e.g. `@%p1 bra LBB1_2;`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub predicate: Option<Predicate>,
+    /// Opcode without modifiers, e.g. `ld` of `ld.param.u64`
+    pub opcode: String,
+    /// Modifiers, e.g. `["param", "u64"]` of `ld.param.u64`
+    pub modifiers: Vec<String>,
+    pub operands: Vec<Operand>,
+}
+
+/// Guard predicate `@%p` or `@!%p`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Predicate {
+    pub negated: bool,
+    pub register: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    /// Register including special registers, e.g. `%r1`, `%tid.x`
+    Register(String),
+    /// Integer or float literal, e.g. `-1`, `0f3F800000`
+    Immediate(String),
+    /// Variable, function or label name
+    Symbol(String),
+    /// `[base]` or `[base+offset]`
+    Address { base: String, offset: Option<i64> },
+    /// `{%f1, %f2}`
+    Vector(Vec<Operand>),
+    /// `(param0, param1)` of `call`
+    List(Vec<Operand>),
+    /// Other operand as raw text
+    Other(String),
+}
+
+impl Module {
+    pub fn parse(src: &str) -> ResultAny<Self> {
+        let tokens = tokenize(src)?;
+        Parser {
+            src,
+            tokens,
+            pos: 0,
+        }
+        .module()
+    }
+
+    pub fn version(&self) -> Option<Version> {
+        self.directives.iter().find_map(|d| match d {
+            Directive::Version(v) => Some(*v),
+            _ => None,
+        })
+    }
+
+    /// Target architecture and options, e.g. `["sm_50"]`
+    pub fn target(&self) -> Option<&[String]> {
+        self.directives.iter().find_map(|d| match d {
+            Directive::Target(t) => Some(t.as_slice()),
+            _ => None,
+        })
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = &Function> {
+        self.directives.iter().filter_map(|d| match d {
+            Directive::Function(f) => Some(f),
+            _ => None,
+        })
+    }
+
+    pub fn functions_mut(&mut self) -> impl Iterator<Item = &mut Function> {
+        self.directives.iter_mut().filter_map(|d| match d {
+            Directive::Function(f) => Some(f),
+            _ => None,
+        })
+    }
+
+    /// Defined `.entry` functions
+    pub fn kernels(&self) -> impl Iterator<Item = &Function> {
+        self.functions()
+            .filter(|f| f.kind == FunctionKind::Entry && f.body.is_some())
+    }
+
+    /// Module-level variables
+    pub fn variables(&self) -> impl Iterator<Item = &Variable> {
+        self.directives.iter().filter_map(|d| match d {
+            Directive::Variable(v) => Some(v),
+            _ => None,
+        })
+    }
+}
+
+impl Function {
+    /// Values of the performance-tuning directive, e.g. `maxntid`
+    pub fn performance(&self, name: &str) -> Option<&[u32]> {
+        self.performance
+            .iter()
+            .find(|d| d.name == name)
+            .map(|d| d.values.as_slice())
+    }
+
+    /// All statements in the body including nested blocks
+    pub fn statements(&self) -> Vec<&Statement> {
+        fn walk<'a>(stmts: &'a [Statement], out: &mut Vec<&'a Statement>) {
+            for s in stmts {
+                out.push(s);
+                if let Statement::Block(inner) = s {
+                    walk(inner, out);
+                }
+            }
+        }
+        let mut out = Vec::new();
+        if let Some(body) = &self.body {
+            walk(body, &mut out);
+        }
+        out
+    }
+
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.statements().into_iter().filter_map(|s| match s {
+            Statement::Instruction(i) => Some(i),
+            _ => None,
+        })
+    }
+
+    /// Variables declared in the body including nested blocks
+    pub fn variables(&self) -> impl Iterator<Item = &Variable> {
+        self.statements().into_iter().filter_map(|s| match s {
+            Statement::Variable(v) => Some(v),
+            _ => None,
+        })
+    }
+}
+
+impl Variable {
+    /// Size in bytes, `None` for registers, predicates, or unsized arrays
+    pub fn size(&self) -> Option<u64> {
+        if self.count.is_some() {
+            return None;
+        }
+        let bits: u64 = match self.ty.as_str() {
+            "f16x2" | "bf16x2" => 32,
+            ty => ty.trim_start_matches(char::is_alphabetic).parse().ok()?,
+        };
+        let mut size = bits / 8 * u64::from(self.vector.unwrap_or(1));
+        for dim in &self.dims {
+            size *= u64::from((*dim)?);
+        }
+        Some(size)
+    }
+}
+
+impl Instruction {
+    /// Opcode with modifiers, e.g. `ld.param.u64`
+    pub fn full_opcode(&self) -> String {
+        let mut op = self.opcode.clone();
+        for m in &self.modifiers {
+            op.push('.');
+            op.push_str(m);
+        }
+        op
+    }
+}
+
+impl StateSpace {
+    fn from_directive(name: &str) -> Option<Self> {
+        Some(match name {
+            ".reg" => StateSpace::Reg,
+            ".sreg" => StateSpace::Sreg,
+            ".const" => StateSpace::Const,
+            ".global" => StateSpace::Global,
+            ".local" => StateSpace::Local,
+            ".param" => StateSpace::Param,
+            ".shared" => StateSpace::Shared,
+            ".tex" => StateSpace::Tex,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StateSpace::Reg => "reg",
+            StateSpace::Sreg => "sreg",
+            StateSpace::Const => "const",
+            StateSpace::Global => "global",
+            StateSpace::Local => "local",
+            StateSpace::Param => "param",
+            StateSpace::Shared => "shared",
+            StateSpace::Tex => "tex",
+        }
+    }
+}
+
+const LINKAGES: [&str; 4] = [".visible", ".extern", ".weak", ".common"];
+
+/*
+ * Tokenizer
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    /// `.entry`, `.u64`
+    Directive,
+    /// Identifier, register, or opcode with modifiers (e.g. `ld.param.u64`)
+    Word,
+    /// Number literal, e.g. `4.0`, `0f3F800000`
+    Number,
+    Str,
+    Punct(char),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    kind: Kind,
+    text: &'a str,
+    start: usize,
+    end: usize,
+    line: usize,
+}
+
+fn tokenize(src: &str) -> ResultAny<Vec<Token<'_>>> {
+    let bytes = src.as_bytes();
+    let is_word = |c: u8| c.is_ascii_alphanumeric() || c == b'_' || c == b'$' || c == b'.';
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+        let kind = match c {
+            b'\n' => {
+                line += 1;
+                i += 1;
+                continue;
+            }
+            _ if c.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                let end = src[i + 2..]
+                    .find("*/")
+                    .ok_or_else(|| err_msg(format!("Unterminated comment at line {}", line)))?;
+                line += src[i..i + 2 + end].matches('\n').count();
+                i += end + 4;
+                continue;
+            }
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                if i >= bytes.len() {
+                    return Err(err_msg(format!("Unterminated string at line {}", line)));
+                }
+                i += 1;
+                Kind::Str
+            }
+            b'.' if bytes
+                .get(i + 1)
+                .is_some_and(|c| c.is_ascii_alphabetic() || *c == b'_') =>
+            {
+                i += 1;
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                Kind::Directive
+            }
+            _ if c.is_ascii_alphabetic() || c == b'_' || c == b'$' || c == b'%' => {
+                i += 1;
+                while i < bytes.len() && is_word(bytes[i]) {
+                    i += 1;
+                }
+                Kind::Word
+            }
+            _ if c.is_ascii_digit() => {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'.') {
+                    i += 1;
+                }
+                Kind::Number
+            }
+            _ if c.is_ascii() => {
+                i += 1;
+                Kind::Punct(c as char)
+            }
+            _ => {
+                return Err(err_msg(format!(
+                    "Unexpected character at line {}: {}",
+                    line,
+                    src[i..].chars().next().unwrap()
+                )))
+            }
+        };
+        tokens.push(Token {
+            kind,
+            text: &src[start..i],
+            start,
+            end: i,
+            line,
+        });
+    }
+    Ok(tokens)
+}
+
+/*
+ * Parser
+ */
+
+struct Parser<'a> {
+    src: &'a str,
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).cloned()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<Token<'a>> {
+        self.tokens.get(self.pos + n).cloned()
+    }
+
+    fn next(&mut self) -> ResultAny<Token<'a>> {
+        let tok = self
+            .peek()
+            .ok_or_else(|| err_msg("Unexpected end of PTX"))?;
+        self.pos += 1;
+        Ok(tok)
+    }
+
+    fn error<T>(&self, msg: &str) -> ResultAny<T> {
+        Err(match self.peek() {
+            Some(tok) => err_msg(format!(
+                "PTX parse error at line {}: {} (found `{}`)",
+                tok.line, msg, tok.text
+            )),
+            None => err_msg(format!("PTX parse error at end: {}", msg)),
+        })
+    }
+
+    fn is_punct(&self, c: char) -> bool {
+        self.peek().is_some_and(|t| t.kind == Kind::Punct(c))
+    }
+
+    fn eat_punct(&mut self, c: char) -> bool {
+        let found = self.is_punct(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_punct(&mut self, c: char) -> ResultAny<()> {
+        if self.eat_punct(c) {
+            Ok(())
+        } else {
+            self.error(&format!("expected `{}`", c))
+        }
+    }
+
+    fn expect(&mut self, kind: Kind, what: &str) -> ResultAny<Token<'a>> {
+        match self.peek() {
+            Some(tok) if tok.kind == kind => {
+                self.pos += 1;
+                Ok(tok)
+            }
+            _ => self.error(&format!("expected {}", what)),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> ResultAny<T> {
+        let tok = self.expect(Kind::Number, "number")?;
+        tok.text
+            .parse()
+            .map_err(|_| err_msg(format!("Invalid number at line {}: {}", tok.line, tok.text)))
+    }
+
+    fn directive_is(&self, offset: usize, pred: impl Fn(&str) -> bool) -> bool {
+        self.peek_at(offset)
+            .is_some_and(|t| t.kind == Kind::Directive && pred(t.text))
+    }
+
+    /// Source text of tokens in `[start, self.pos)`
+    fn raw(&self, start: usize) -> String {
+        if start >= self.pos {
+            return String::new();
+        }
+        self.src[self.tokens[start].start..self.tokens[self.pos - 1].end].to_string()
+    }
+
+    fn module(&mut self) -> ResultAny<Module> {
+        let mut directives = Vec::new();
+        while self.peek().is_some() {
+            directives.push(self.directive()?);
+        }
+        Ok(Module { directives })
+    }
+
+    fn directive(&mut self) -> ResultAny<Directive> {
+        let tok = self.peek().unwrap();
+        match tok.text {
+            ".version" => {
+                self.pos += 1;
+                let v = self.expect(Kind::Number, "version")?;
+                let version = v
+                    .text
+                    .parse()
+                    .map_err(|e| err_msg(format!("{} at line {}", e, v.line)))?;
+                Ok(Directive::Version(version))
+            }
+            ".target" => {
+                self.pos += 1;
+                let mut target = vec![self.expect(Kind::Word, "target")?.text.to_string()];
+                while self.eat_punct(',') {
+                    target.push(self.expect(Kind::Word, "target")?.text.to_string());
+                }
+                Ok(Directive::Target(target))
+            }
+            ".address_size" => {
+                self.pos += 1;
+                Ok(Directive::AddressSize(self.number()?))
+            }
+            _ => {
+                let linked = LINKAGES.contains(&tok.text) as usize;
+                if self.directive_is(linked, |d| d == ".entry" || d == ".func") {
+                    Ok(Directive::Function(self.function()?))
+                } else if self.directive_is(linked, |d| StateSpace::from_directive(d).is_some()) {
+                    let var = self.variable()?;
+                    self.expect_punct(';')?;
+                    Ok(Directive::Variable(var))
+                } else {
+                    Ok(Directive::Other(self.other()?))
+                }
+            }
+        }
+    }
+
+    fn linkage(&mut self) -> Option<String> {
+        if self.directive_is(0, |d| LINKAGES.contains(&d)) {
+            let tok = self.peek().unwrap();
+            self.pos += 1;
+            Some(tok.text[1..].to_string())
+        } else {
+            None
+        }
+    }
+
+    fn function(&mut self) -> ResultAny<Function> {
+        let linkage = self.linkage();
+        let kind = match self.next()?.text {
+            ".entry" => FunctionKind::Entry,
+            _ => FunctionKind::Func,
+        };
+        let returns = if self.is_punct('(') {
+            self.params()?
+        } else {
+            Vec::new()
+        };
+        let name = self.expect(Kind::Word, "function name")?.text.to_string();
+        let params = if self.is_punct('(') {
+            self.params()?
+        } else {
+            Vec::new()
+        };
+        let mut performance = Vec::new();
+        while let Some(tok) = self.peek().filter(|t| t.kind == Kind::Directive) {
+            self.pos += 1;
+            let mut values = Vec::new();
+            if self.peek().is_some_and(|t| t.kind == Kind::Number) {
+                values.push(self.number()?);
+                while self.eat_punct(',') {
+                    values.push(self.number()?);
+                }
+            }
+            performance.push(PerformanceDirective {
+                name: tok.text[1..].to_string(),
+                values,
+            });
+        }
+        let body = if self.eat_punct(';') {
+            None
+        } else {
+            Some(self.block()?)
+        };
+        Ok(Function {
+            linkage,
+            kind,
+            name,
+            returns,
+            params,
+            performance,
+            body,
+        })
+    }
+
+    fn params(&mut self) -> ResultAny<Vec<Variable>> {
+        self.expect_punct('(')?;
+        let mut params = Vec::new();
+        if self.eat_punct(')') {
+            return Ok(params);
+        }
+        loop {
+            params.push(self.variable()?);
+            if !self.eat_punct(',') {
+                break;
+            }
+        }
+        self.expect_punct(')')?;
+        Ok(params)
+    }
+
+    fn variable(&mut self) -> ResultAny<Variable> {
+        let linkage = self.linkage();
+        let space = match self.peek().and_then(|t| StateSpace::from_directive(t.text)) {
+            Some(space) => space,
+            None => return self.error("expected state space"),
+        };
+        self.pos += 1;
+        let mut var = Variable {
+            linkage,
+            space,
+            align: None,
+            vector: None,
+            ty: String::new(),
+            ptr: None,
+            name: String::new(),
+            count: None,
+            dims: Vec::new(),
+            init: None,
+        };
+        while let Some(tok) = self.peek().filter(|t| t.kind == Kind::Directive) {
+            self.pos += 1;
+            match tok.text {
+                ".align" => var.align = Some(self.number()?),
+                ".v2" | ".v4" | ".v8" => var.vector = Some(tok.text[2..].parse().unwrap()),
+                ".ptr" => {
+                    let space = self.peek().and_then(|t| StateSpace::from_directive(t.text));
+                    if space.is_some() {
+                        self.pos += 1;
+                    }
+                    let align = if self.directive_is(0, |d| d == ".align") {
+                        self.pos += 1;
+                        Some(self.number()?)
+                    } else {
+                        None
+                    };
+                    var.ptr = Some(PtrAttribute { space, align });
+                }
+                _ => var.ty = tok.text[1..].to_string(),
+            }
+        }
+        var.name = self.expect(Kind::Word, "variable name")?.text.to_string();
+        if self.eat_punct('<') {
+            var.count = Some(self.number()?);
+            self.expect_punct('>')?;
+        }
+        while self.eat_punct('[') {
+            if self.eat_punct(']') {
+                var.dims.push(None);
+            } else {
+                var.dims.push(Some(self.number()?));
+                self.expect_punct(']')?;
+            }
+        }
+        if self.eat_punct('=') {
+            let start = self.pos;
+            let mut depth = 0;
+            while let Some(tok) = self.peek() {
+                match tok.kind {
+                    Kind::Punct('{') => depth += 1,
+                    Kind::Punct('}') => depth -= 1,
+                    Kind::Punct(';') if depth == 0 => break,
+                    _ => {}
+                }
+                self.pos += 1;
+            }
+            var.init = Some(self.raw(start));
+        }
+        Ok(var)
+    }
+
+    /// Directive which is not modeled, until `;`, end of the line, or closing brace
+    fn other(&mut self) -> ResultAny<String> {
+        let start = self.pos;
+        let first = self.next()?;
+        // Debug directives are terminated by newline
+        let line_based = first.text == ".file" || first.text == ".loc";
+        let mut depth = 0;
+        while let Some(tok) = self.peek() {
+            if line_based && tok.line != first.line {
+                break;
+            }
+            self.pos += 1;
+            match tok.kind {
+                Kind::Punct('{') => depth += 1,
+                Kind::Punct('}') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                Kind::Punct(';') if depth == 0 => break,
+                _ => {}
+            }
+        }
+        Ok(self.raw(start))
+    }
+
+    fn block(&mut self) -> ResultAny<Vec<Statement>> {
+        self.expect_punct('{')?;
+        let mut stmts = Vec::new();
+        while !self.eat_punct('}') {
+            if self.peek().is_none() {
+                return self.error("expected `}`");
+            }
+            stmts.push(self.statement()?);
+        }
+        Ok(stmts)
+    }
+
+    fn statement(&mut self) -> ResultAny<Statement> {
+        let tok = self.peek().unwrap();
+        match tok.kind {
+            Kind::Punct('{') => Ok(Statement::Block(self.block()?)),
+            Kind::Punct('@') => Ok(Statement::Instruction(self.instruction()?)),
+            Kind::Word if self.peek_at(1).is_some_and(|t| t.kind == Kind::Punct(':')) => {
+                self.pos += 2;
+                Ok(Statement::Label(tok.text.to_string()))
+            }
+            Kind::Word => Ok(Statement::Instruction(self.instruction()?)),
+            Kind::Directive => {
+                let linked = LINKAGES.contains(&tok.text) as usize;
+                if self.directive_is(linked, |d| StateSpace::from_directive(d).is_some()) {
+                    let var = self.variable()?;
+                    self.expect_punct(';')?;
+                    Ok(Statement::Variable(var))
+                } else {
+                    Ok(Statement::Other(self.other()?))
+                }
+            }
+            _ => self.error("expected statement"),
+        }
+    }
+
+    fn instruction(&mut self) -> ResultAny<Instruction> {
+        let predicate = if self.eat_punct('@') {
+            let negated = self.eat_punct('!');
+            let register = self.expect(Kind::Word, "predicate")?.text.to_string();
+            Some(Predicate { negated, register })
+        } else {
+            None
+        };
+        let op = self.expect(Kind::Word, "opcode")?.text;
+        let mut parts = op.split('.');
+        let opcode = parts.next().unwrap().to_string();
+        let modifiers = parts.map(|s| s.to_string()).collect();
+        let mut operands = Vec::new();
+        if !self.eat_punct(';') {
+            loop {
+                operands.push(self.operand()?);
+                if !self.eat_punct(',') {
+                    break;
+                }
+            }
+            self.expect_punct(';')?;
+        }
+        Ok(Instruction {
+            predicate,
+            opcode,
+            modifiers,
+            operands,
+        })
+    }
+
+    fn is_operand_end(&self) -> bool {
+        match self.peek() {
+            Some(t) => matches!(t.kind, Kind::Punct(',' | ';' | '}' | ')')),
+            None => true,
+        }
+    }
+
+    fn operand(&mut self) -> ResultAny<Operand> {
+        let start = self.pos;
+        if let Some(op) = self.typed_operand()? {
+            if self.is_operand_end() {
+                return Ok(op);
+            }
+        }
+        // Fallback to raw text until the end of the operand
+        self.pos = start;
+        let mut depth = 0;
+        while let Some(tok) = self.peek() {
+            match tok.kind {
+                Kind::Punct('[' | '{' | '(') => depth += 1,
+                Kind::Punct(']' | '}' | ')') if depth > 0 => depth -= 1,
+                _ if depth == 0 && self.is_operand_end() => break,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return self.error("expected operand");
+        }
+        Ok(Operand::Other(self.raw(start)))
+    }
+
+    /// Parse an operand, or `None` if it is not in the supported forms
+    fn typed_operand(&mut self) -> ResultAny<Option<Operand>> {
+        let tok = match self.peek() {
+            Some(tok) => tok,
+            None => return Ok(None),
+        };
+        self.pos += 1;
+        Ok(Some(match tok.kind {
+            Kind::Word if tok.text.starts_with('%') => Operand::Register(tok.text.into()),
+            Kind::Word => Operand::Symbol(tok.text.into()),
+            Kind::Number => Operand::Immediate(tok.text.into()),
+            Kind::Punct('-') => match self.peek() {
+                Some(num) if num.kind == Kind::Number => {
+                    self.pos += 1;
+                    Operand::Immediate(format!("-{}", num.text))
+                }
+                _ => return Ok(None),
+            },
+            Kind::Punct('[') => {
+                let base = match self.peek() {
+                    Some(t) if t.kind == Kind::Word || t.kind == Kind::Number => t.text,
+                    _ => return Ok(None),
+                };
+                self.pos += 1;
+                let sign = if self.eat_punct('+') {
+                    1
+                } else if self.eat_punct('-') {
+                    -1
+                } else {
+                    0
+                };
+                let offset = if sign != 0 {
+                    match self.peek().and_then(|t| t.text.parse::<i64>().ok()) {
+                        Some(n) => {
+                            self.pos += 1;
+                            Some(sign * n)
+                        }
+                        None => return Ok(None),
+                    }
+                } else {
+                    None
+                };
+                if !self.eat_punct(']') {
+                    return Ok(None);
+                }
+                Operand::Address {
+                    base: base.into(),
+                    offset,
+                }
+            }
+            Kind::Punct(open @ ('{' | '(')) => {
+                let close = if open == '{' { '}' } else { ')' };
+                let mut items = Vec::new();
+                if !self.eat_punct(close) {
+                    loop {
+                        items.push(self.operand()?);
+                        if !self.eat_punct(',') {
+                            break;
+                        }
+                    }
+                    if !self.eat_punct(close) {
+                        return Ok(None);
+                    }
+                }
+                if open == '{' {
+                    Operand::Vector(items)
+                } else {
+                    Operand::List(items)
+                }
+            }
+            _ => return Ok(None),
+        }))
+    }
+}
+
+/*
+ * Printer
+ */
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, d) in self.directives.iter().enumerate() {
+            // Separate header and functions by blank lines like llc
+            let separate = i + 1 < self.directives.len()
+                && matches!(d, Directive::Function(_) | Directive::AddressSize(_));
+            writeln!(f, "{}", d)?;
+            if separate {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Directive::Version(v) => write!(f, ".version {}", v),
+            Directive::Target(t) => write!(f, ".target {}", t.join(", ")),
+            Directive::AddressSize(size) => write!(f, ".address_size {}", size),
+            Directive::Variable(v) => write!(f, "{};", v),
+            Directive::Function(func) => write!(f, "{}", func),
+            Directive::Other(raw) => write!(f, "{}", raw),
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = failure::Error;
+
+    /// Parse `major.minor`, e.g. `6.0`
+    fn from_str(s: &str) -> ResultAny<Self> {
+        let mut it = s.splitn(2, '.');
+        let major = it.next().and_then(|s| s.parse().ok());
+        let minor = it.next().and_then(|s| s.parse().ok());
+        match (major, minor) {
+            (Some(major), Some(minor)) => Ok(Version { major, minor }),
+            _ => Err(err_msg(format!("Invalid PTX version: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(linkage) = &self.linkage {
+            write!(f, ".{} ", linkage)?;
+        }
+        match self.kind {
+            FunctionKind::Entry => write!(f, ".entry ")?,
+            FunctionKind::Func => write!(f, ".func ")?,
+        }
+        if !self.returns.is_empty() {
+            let returns: Vec<_> = self.returns.iter().map(|v| v.to_string()).collect();
+            write!(f, "({}) ", returns.join(", "))?;
+        }
+        write!(f, "{}", self.name)?;
+        if self.params.is_empty() {
+            write!(f, "()")?;
+        } else {
+            writeln!(f, "(")?;
+            for (i, param) in self.params.iter().enumerate() {
+                let sep = if i + 1 < self.params.len() { "," } else { "" };
+                writeln!(f, "\t{}{}", param, sep)?;
+            }
+            write!(f, ")")?;
+        }
+        for d in &self.performance {
+            write!(f, "\n.{}", d.name)?;
+            let values: Vec<_> = d.values.iter().map(|v| v.to_string()).collect();
+            if !values.is_empty() {
+                write!(f, " {}", values.join(", "))?;
+            }
+        }
+        match &self.body {
+            Some(body) => {
+                writeln!(f, "\n{{")?;
+                write_statements(f, body, 1)?;
+                write!(f, "}}")
+            }
+            None => write!(f, ";"),
+        }
+    }
+}
+
+fn write_statements(f: &mut fmt::Formatter, stmts: &[Statement], depth: usize) -> fmt::Result {
+    let indent = "\t".repeat(depth);
+    for s in stmts {
+        match s {
+            Statement::Label(label) => writeln!(f, "{}:", label)?,
+            Statement::Variable(v) => writeln!(f, "{}{};", indent, v)?,
+            Statement::Instruction(i) => writeln!(f, "{}{}", indent, i)?,
+            Statement::Block(inner) => {
+                writeln!(f, "{}{{", indent)?;
+                write_statements(f, inner, depth + 1)?;
+                writeln!(f, "{}}}", indent)?;
+            }
+            Statement::Other(raw) => writeln!(f, "{}{}", indent, raw)?,
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(linkage) = &self.linkage {
+            write!(f, ".{} ", linkage)?;
+        }
+        write!(f, ".{}", self.space.name())?;
+        if let Some(align) = self.align {
+            write!(f, " .align {}", align)?;
+        }
+        if let Some(n) = self.vector {
+            write!(f, " .v{}", n)?;
+        }
+        write!(f, " .{}", self.ty)?;
+        if let Some(ptr) = &self.ptr {
+            write!(f, " .ptr")?;
+            if let Some(space) = ptr.space {
+                write!(f, " .{}", space.name())?;
+            }
+            if let Some(align) = ptr.align {
+                write!(f, " .align {}", align)?;
+            }
+        }
+        write!(f, " {}", self.name)?;
+        if let Some(count) = self.count {
+            write!(f, "<{}>", count)?;
+        }
+        for dim in &self.dims {
+            match dim {
+                Some(n) => write!(f, "[{}]", n)?,
+                None => write!(f, "[]")?,
+            }
+        }
+        if let Some(init) = &self.init {
+            write!(f, " = {}", init)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(p) = &self.predicate {
+            write!(f, "@{}{} ", if p.negated { "!" } else { "" }, p.register)?;
+        }
+        write!(f, "{}", self.full_opcode())?;
+        if !self.operands.is_empty() {
+            write!(f, " \t{}", join(&self.operands))?;
+        }
+        write!(f, ";")
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(s)
+            | Operand::Immediate(s)
+            | Operand::Symbol(s)
+            | Operand::Other(s) => {
+                write!(f, "{}", s)
+            }
+            Operand::Address { base, offset } => match offset {
+                Some(n) if *n < 0 => write!(f, "[{}{}]", base, n),
+                Some(n) => write!(f, "[{}+{}]", base, n),
+                None => write!(f, "[{}]", base),
+            },
+            Operand::Vector(items) => write!(f, "{{{}}}", join(items)),
+            Operand::List(items) => write!(f, "({})", join(items)),
+        }
+    }
+}
+
+fn join(operands: &[Operand]) -> String {
+    operands
+        .iter()
+        .map(|op| op.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Generated by `llc -mcpu=sm_50 fixtures/add.ll`
+    const ADD_PTX: &str = include_str!("../fixtures/add.ptx");
+
+    #[test]
+    fn parse_fixture() {
+        let ptx = Module::parse(ADD_PTX).unwrap();
+        assert_eq!(ptx.version(), Some(Version { major: 4, minor: 0 }));
+        assert_eq!(ptx.target(), Some(&["sm_50".to_string()][..]));
+
+        let vars: Vec<_> = ptx.variables().collect();
+        assert_eq!(vars[0].name, "table");
+        assert_eq!(vars[0].space, StateSpace::Global);
+        assert_eq!(vars[0].size(), Some(16));
+        assert_eq!(vars[1].init, Some("0f40000000".into()));
+
+        let square = ptx.functions().next().unwrap();
+        assert_eq!(square.kind, FunctionKind::Func);
+        assert_eq!(square.returns[0].name, "func_retval0");
+
+        let kernels: Vec<_> = ptx.kernels().collect();
+        assert_eq!(kernels.len(), 1);
+        let add = kernels[0];
+        assert_eq!(add.params.len(), 4);
+        assert_eq!(add.params[3].ty, "u64");
+        let shared: Vec<_> = add
+            .variables()
+            .filter(|v| v.space == StateSpace::Shared)
+            .collect();
+        assert_eq!(shared[0].size(), Some(1024));
+
+        let bra = add.instructions().find(|i| i.opcode == "bra").unwrap();
+        assert_eq!(
+            bra.predicate,
+            Some(Predicate {
+                negated: false,
+                register: "%p1".into()
+            })
+        );
+        let call = add.instructions().find(|i| i.opcode == "call").unwrap();
+        assert_eq!(
+            call.operands,
+            vec![
+                Operand::List(vec![Operand::Symbol("retval0".into())]),
+                Operand::Symbol("square".into()),
+                Operand::List(vec![Operand::Symbol("param0".into())]),
+            ]
+        );
+        let ld = add
+            .instructions()
+            .find(|i| i.full_opcode() == "ld.global.u32")
+            .unwrap();
+        assert_eq!(
+            ld.operands[1],
+            Operand::Address {
+                base: "table".into(),
+                offset: Some(8)
+            }
+        );
+    }
+
+    #[test]
+    fn roundtrip() {
+        let ptx = Module::parse(ADD_PTX).unwrap();
+        let printed = ptx.to_string();
+        assert_eq!(Module::parse(&printed).unwrap(), ptx);
+        assert!(printed.contains("\tld.param.u64 \t%rd8, [add_param_3];\n"));
+        assert!(printed.contains("\t.shared .align 4 .b8 cache[1024];\n"));
+    }
+
+    #[test]
+    fn parse_attributes() {
+        let ptx = Module::parse(
+            r#"
+.extern .shared .align 16 .b8 smem[];
+.visible .entry k(
+	.param .u64 .ptr .global .align 8 k_param_0,
+	.param .align 8 .b8 k_param_1[16]
+)
+.maxntid 256, 1, 1
+.minnctapersm 2
+{
+	.loc 1 2 0
+	@!%p1 st.global.v2.f32 [%rd1+-4], {%f1, %f2};
+	setp.lt.s32 %p1|%p2, %r1, -1;
+}
+"#,
+        )
+        .unwrap();
+        let smem = ptx.variables().next().unwrap();
+        assert_eq!(smem.linkage, Some("extern".into()));
+        assert_eq!(smem.dims, vec![None]);
+        assert_eq!(smem.size(), None);
+        let k = ptx.kernels().next().unwrap();
+        assert_eq!(
+            k.params[0].ptr,
+            Some(PtrAttribute {
+                space: Some(StateSpace::Global),
+                align: Some(8)
+            })
+        );
+        assert_eq!(k.params[1].size(), Some(16));
+        assert_eq!(k.performance("maxntid"), Some(&[256, 1, 1][..]));
+        let body = k.body.as_ref().unwrap();
+        assert_eq!(body[0], Statement::Other(".loc 1 2 0".into()));
+        let insts: Vec<_> = k.instructions().collect();
+        assert!(insts[0].predicate.as_ref().unwrap().negated);
+        assert_eq!(insts[0].operands[0], Operand::Other("[%rd1+-4]".into()));
+        assert_eq!(insts[1].operands[0], Operand::Other("%p1|%p2".into()));
+        assert_eq!(insts[1].operands[2], Operand::Immediate("-1".into()));
+        assert_eq!(Module::parse(&ptx.to_string()).unwrap(), ptx);
+    }
+}
@@ -0,0 +1,226 @@
+//! Rewrite `.version` and `.target` directives of PTX
+//!
+//! `llc` emits the PTX ISA version of its LLVM release, which may be newer than
+//! the version supported by the deployed CUDA driver. The module can be retargeted
+//! to an older version if it does not use instructions introduced after that version.
+
+use failure::err_msg;
+
+use crate::error::ResultAny;
+use crate::ptx::{Directive, Instruction, Module, Version};
+
+/// Minimum PTX ISA version supporting the architecture
+const ARCH_VERSIONS: [(u32, Version); 19] = [
+    (20, v(2, 0)),
+    (30, v(3, 0)),
+    (32, v(4, 0)),
+    (35, v(3, 1)),
+    (37, v(4, 1)),
+    (50, v(4, 0)),
+    (52, v(4, 1)),
+    (53, v(4, 2)),
+    (60, v(5, 0)),
+    (61, v(5, 0)),
+    (62, v(5, 0)),
+    (70, v(6, 0)),
+    (72, v(6, 1)),
+    (75, v(6, 3)),
+    (80, v(7, 0)),
+    (86, v(7, 1)),
+    (87, v(7, 4)),
+    (89, v(7, 8)),
+    (90, v(7, 8)),
+];
+
+/// Instruction requiring a PTX ISA version and an architecture
+struct Rule {
+    opcode: &'static str,
+    /// All of them must be in the modifiers of the instruction
+    modifiers: &'static [&'static str],
+    version: Version,
+    sm: u32,
+}
+
+const fn v(major: u32, minor: u32) -> Version {
+    Version { major, minor }
+}
+
+const fn rule(
+    opcode: &'static str,
+    modifiers: &'static [&'static str],
+    version: Version,
+    sm: u32,
+) -> Rule {
+    Rule {
+        opcode,
+        modifiers,
+        version,
+        sm,
+    }
+}
+
+const RULES: [Rule; 27] = [
+    rule("ld", &["nc"], v(3, 1), 32),
+    rule("add", &["f16"], v(4, 2), 53),
+    rule("add", &["f16x2"], v(4, 2), 53),
+    rule("mul", &["f16"], v(4, 2), 53),
+    rule("mul", &["f16x2"], v(4, 2), 53),
+    rule("fma", &["f16"], v(4, 2), 53),
+    rule("fma", &["f16x2"], v(4, 2), 53),
+    rule("atom", &["add", "f64"], v(5, 0), 60),
+    rule("red", &["add", "f64"], v(5, 0), 60),
+    rule("atom", &["sys"], v(5, 0), 60),
+    rule("atom", &["cta"], v(5, 0), 60),
+    rule("dp4a", &[], v(5, 0), 61),
+    rule("dp2a", &[], v(5, 0), 61),
+    rule("shfl", &["sync"], v(6, 0), 30),
+    rule("vote", &["sync"], v(6, 0), 30),
+    rule("bar", &["warp"], v(6, 0), 30),
+    rule("match", &[], v(6, 0), 70),
+    rule("fence", &[], v(6, 0), 70),
+    rule("ld", &["acquire"], v(6, 0), 70),
+    rule("st", &["release"], v(6, 0), 70),
+    rule("wmma", &[], v(6, 0), 70),
+    rule("activemask", &[], v(6, 2), 30),
+    rule("nanosleep", &[], v(6, 3), 70),
+    rule("mma", &[], v(6, 4), 70),
+    rule("cp", &["async"], v(7, 0), 80),
+    rule("redux", &["sync"], v(7, 0), 80),
+    rule("alloca", &[], v(7, 3), 52),
+];
+
+/// Numeric part of the architecture, e.g. `70` of `sm_70`
+fn sm_number(arch: &str) -> Option<u32> {
+    arch.strip_prefix("sm_")?
+        .trim_end_matches(char::is_alphabetic)
+        .parse()
+        .ok()
+}
+
+/// Minimum PTX ISA version for the architecture, `None` if unknown
+pub fn min_version(arch: &str) -> Option<Version> {
+    let sm = sm_number(arch)?;
+    ARCH_VERSIONS
+        .iter()
+        .find(|(n, _)| *n == sm)
+        .map(|(_, version)| *version)
+}
+
+fn matches(rule: &Rule, inst: &Instruction) -> bool {
+    inst.opcode == rule.opcode
+        && rule
+            .modifiers
+            .iter()
+            .all(|m| inst.modifiers.iter().any(|n| n == m))
+}
+
+/// Rewrite `.version` and `.target` of the module.
+///
+/// The target options (e.g. `debug`) are kept. This fails if the architecture is not
+/// supported in the version, or the module uses instructions unavailable in them.
+pub fn retarget(
+    module: &mut Module,
+    version: Option<Version>,
+    arch: Option<&str>,
+) -> ResultAny<()> {
+    // Both directives are rewritten in place, so they must exist even if given explicitly
+    let version = match (module.version(), version) {
+        (Some(current), requested) => requested.unwrap_or(current),
+        (None, _) => return Err(err_msg("PTX has no .version directive")),
+    };
+    let arch = match (module.target().and_then(|t| t.first()), arch) {
+        (Some(current), requested) => requested.unwrap_or(current).to_string(),
+        (None, _) => return Err(err_msg("PTX has no .target directive")),
+    };
+    if let Some(required) = min_version(&arch) {
+        if required > version {
+            return Err(err_msg(format!(
+                "{} requires PTX ISA {}, but {} is requested",
+                arch, required, version
+            )));
+        }
+    }
+    if let Some(sm) = sm_number(&arch) {
+        for func in module.functions() {
+            for inst in func.instructions() {
+                for rule in RULES.iter().filter(|r| matches(r, inst)) {
+                    if rule.version > version || rule.sm > sm {
+                        return Err(err_msg(format!(
+                            "`{}` in `{}` requires PTX ISA {} and sm_{}, but PTX ISA {} and {} are requested",
+                            inst.full_opcode(),
+                            func.name,
+                            rule.version,
+                            rule.sm,
+                            version,
+                            arch
+                        )));
+                    }
+                }
+            }
+        }
+    }
+    for d in &mut module.directives {
+        match d {
+            Directive::Version(v) => *v = version,
+            Directive::Target(t) if !t.is_empty() => t[0] = arch.clone(),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PTX: &str = r#"
+.version 7.0
+.target sm_70, debug
+.address_size 64
+
+.visible .entry k()
+{
+	.reg .b32 %r<3>;
+	shfl.sync.bfly.b32 %r1, %r2, 1, 31, -1;
+	ret;
+}
+"#;
+
+    #[test]
+    fn downgrade() {
+        let mut module = Module::parse(PTX).unwrap();
+        retarget(&mut module, Some(v(6, 0)), Some("sm_60")).unwrap();
+        assert_eq!(module.version(), Some(v(6, 0)));
+        assert_eq!(
+            module.target(),
+            Some(&["sm_60".to_string(), "debug".to_string()][..])
+        );
+        assert!(module
+            .to_string()
+            .contains(".version 6.0\n.target sm_60, debug\n"));
+    }
+
+    #[test]
+    fn invalid_downgrade() {
+        let mut module = Module::parse(PTX).unwrap();
+        let e = retarget(&mut module, Some(v(5, 0)), Some("sm_60")).unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("`shfl.sync.bfly.b32` in `k` requires PTX ISA 6.0"));
+        let e = retarget(&mut module, Some(v(5, 0)), None).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "sm_70 requires PTX ISA 6.0, but 5.0 is requested"
+        );
+        // Unchanged on error
+        assert_eq!(module.version(), Some(v(7, 0)));
+    }
+
+    #[test]
+    fn missing_target() {
+        let mut module = Module::parse(".version 7.0\n.address_size 64\n").unwrap();
+        let e = retarget(&mut module, Some(v(6, 0)), Some("sm_60")).unwrap_err();
+        assert_eq!(e.to_string(), "PTX has no .target directive");
+        assert_eq!(module.version(), Some(v(7, 0)));
+    }
+}
@@ -0,0 +1,216 @@
+//! Templates of kernel crates for `nvptx new` and `nvptx init`
+//!
+//! ```text
+//! {name}/
+//! ├── Cargo.toml
+//! ├── src/lib.rs
+//! └── host/             # with_host
+//!     ├── Cargo.toml
+//!     ├── build.rs      # compile the kernel crate by nvptx::build::Builder
+//!     └── src/main.rs
+//! ```
+
+use colored::*;
+use failure::err_msg;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::*;
+use toml::Value;
+
+use crate::error::ResultAny;
+use crate::kernel::nvptx_panic;
+use crate::manifest::{CargoTOML, Crate, Metadata, NvptxMetadata, Package, Profiles};
+
+const KERNEL_LIB: &str = r#"#![feature(abi_ptx, lang_items, core_intrinsics)]
+#![no_std]
+
+extern crate accel_core;
+extern crate nvptx_panic;
+
+#[no_mangle]
+pub unsafe extern "ptx-kernel" fn add(a: *const f64, b: *const f64, c: *mut f64, n: usize) {
+    let i = accel_core::index();
+    if (i as usize) < n {
+        *c.offset(i) = *a.offset(i) + *b.offset(i);
+    }
+}
+"#;
+
+const HOST_BUILD: &str = r#"fn main() {
+    nvptx::build::Builder::new("..")
+        .set_name("{name}")
+        .build()
+        .unwrap();
+}
+"#;
+
+const HOST_MAIN: &str = r#"/// PTX of the kernel crate compiled in build.rs
+const PTX: &str = include_str!(concat!(env!("OUT_DIR"), "/{name}.ptx"));
+
+fn main() {
+    println!("{}", PTX);
+}
+"#;
+
+/// Generator of a kernel crate
+pub struct Scaffold {
+    path: PathBuf,
+    name: Option<String>,
+    host: bool,
+}
+
+impl Scaffold {
+    /// Kernel crate at the path
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Scaffold {
+            path: path.as_ref().to_owned(),
+            name: None,
+            host: false,
+        }
+    }
+
+    /// Package name (default: directory name)
+    pub fn set_name(&mut self, name: &str) {
+        self.name = Some(name.into());
+    }
+
+    /// Generate host-side crate in `host/` which compiles the kernel crate in build.rs
+    pub fn with_host(&mut self) {
+        self.host = true;
+    }
+
+    pub fn name(&self) -> ResultAny<String> {
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => fs::canonicalize(&self.path)
+                .unwrap_or_else(|_| self.path.clone())
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| err_msg(format!("Invalid path: {}", self.path.display())))?
+                .into(),
+        };
+        let valid = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if name.is_empty() || !valid || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(err_msg(format!("Invalid package name: {}", name)));
+        }
+        Ok(name)
+    }
+
+    /// Create a new directory for the crate (`nvptx new`), and returns the created files
+    pub fn create(&self) -> ResultAny<Vec<PathBuf>> {
+        if self.path.exists() {
+            return Err(err_msg(format!(
+                "Destination {} already exists",
+                self.path.display()
+            )));
+        }
+        fs::create_dir_all(&self.path)?;
+        self.generate()
+    }
+
+    /// Create the crate in the existing directory (`nvptx init`), and returns the created files
+    pub fn init(&self) -> ResultAny<Vec<PathBuf>> {
+        fs::create_dir_all(&self.path)?;
+        self.generate()
+    }
+
+    fn generate(&self) -> ResultAny<Vec<PathBuf>> {
+        let name = self.name()?;
+        let mut files = vec![
+            (self.path.join("Cargo.toml"), kernel_manifest(&name)),
+            (self.path.join("src/lib.rs"), KERNEL_LIB.to_string()),
+        ];
+        if self.host {
+            let host = self.path.join("host");
+            files.push((host.join("Cargo.toml"), host_manifest(&name)?));
+            files.push((host.join("build.rs"), HOST_BUILD.replace("{name}", &name)));
+            files.push((host.join("src/main.rs"), HOST_MAIN.replace("{name}", &name)));
+        }
+        // Check all files before writing not to leave a half-generated crate
+        for (path, _) in &files {
+            if path.exists() {
+                return Err(err_msg(format!("{} already exists", path.display())));
+            }
+        }
+        for (path, contents) in &files {
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, contents)?;
+        }
+        eprintln!(
+            "{:>12} kernel crate `{}`{}",
+            "Created".bright_green(),
+            name,
+            if self.host { " with host crate" } else { "" }
+        );
+        Ok(files.into_iter().map(|(path, _)| path).collect())
+    }
+}
+
+fn package(name: &str) -> Package {
+    Package {
+        name: name.into(),
+        edition: Some("2018".into()),
+        ..Default::default()
+    }
+}
+
+fn kernel_manifest(name: &str) -> String {
+    let mut setting = CargoTOML::from_crates(&[Crate::latest("accel-core"), nvptx_panic()]);
+    setting.package = Package {
+        metadata: Some(Metadata {
+            nvptx: Some(NvptxMetadata {
+                runtime: vec!["core".into()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        ..package(name)
+    };
+    setting.profile = Profiles::default();
+    setting.as_toml()
+}
+
+fn host_manifest(name: &str) -> ResultAny<String> {
+    let mut setting = CargoTOML::from_crates(&[]);
+    setting.package = package(&format!("{}-host", name));
+    setting.profile = Profiles::default();
+    let mut build_deps = BTreeMap::new();
+    build_deps.insert(
+        "nvptx",
+        Crate::new("nvptx", env!("CARGO_PKG_VERSION")).dependency(),
+    );
+    setting
+        .extra
+        .insert("build-dependencies".into(), Value::try_from(build_deps)?);
+    Ok(setting.as_toml())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_with_host() {
+        let dir = tempdir::TempDir::new("nvptx-scaffold").unwrap();
+        let path = dir.path().join("my-kernel");
+        let mut scaffold = Scaffold::new(&path);
+        scaffold.with_host();
+        let files = scaffold.create().unwrap();
+        assert_eq!(files.len(), 5);
+
+        let kernel = CargoTOML::load(&path).unwrap();
+        assert_eq!(kernel.package.name, "my-kernel");
+        assert_eq!(kernel.runtime(), vec!["core"]);
+        assert!(kernel.dependencies.contains_key("nvptx-panic"));
+        let host = CargoTOML::load(path.join("host")).unwrap();
+        assert_eq!(host.package.name, "my-kernel-host");
+        assert!(host.extra.contains_key("build-dependencies"));
+        let build = fs::read_to_string(path.join("host/build.rs")).unwrap();
+        assert!(build.contains(r#".set_name("my-kernel")"#));
+
+        assert!(scaffold.create().is_err());
+        assert!(scaffold.init().is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! Build state saved in the target directory for incremental rebuild
+//!
+//! The state records fingerprints of the input files (rlibs and runtime bitcodes)
+//! and the settings used to generate PTX. If nothing changed since the last build,
+//! link/opt/llc steps are skipped.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::*;
+use std::time::UNIX_EPOCH;
+
+use crate::error::ResultAny;
+
+/// Size and modification time of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    len: u64,
+    secs: u64,
+    nanos: u32,
+}
+
+impl Fingerprint {
+    pub fn of(path: &Path) -> ResultAny<Self> {
+        let meta = fs::metadata(path)?;
+        let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?;
+        Ok(Fingerprint {
+            len: meta.len(),
+            secs: mtime.as_secs(),
+            nanos: mtime.subsec_nanos(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BuildState {
+    /// Settings affecting the output, e.g. `arch = "sm_50"`
+    pub settings: BTreeMap<String, String>,
+    /// Fingerprints of input files
+    pub inputs: BTreeMap<PathBuf, Fingerprint>,
+}
+
+impl BuildState {
+    /// Load the state, or empty state if not exists or broken
+    pub fn load(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> ResultAny<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn setting<S: ToString>(&mut self, key: &str, value: S) {
+        self.settings.insert(key.into(), value.to_string());
+    }
+
+    /// Record the fingerprint of the input, and returns if it is unchanged from the previous state
+    pub fn track(&mut self, path: &Path, prev: &BuildState) -> ResultAny<bool> {
+        let fp = Fingerprint::of(path)?;
+        self.inputs.insert(path.to_owned(), fp);
+        Ok(prev.inputs.get(path) == Some(&fp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn track_inputs() {
+        let dir = TempDir::new("nvptx-state").unwrap();
+        let input = dir.path().join("libfoo.rlib");
+        fs::write(&input, "foo").unwrap();
+
+        let mut prev = BuildState::default();
+        prev.setting("arch", "sm_50");
+        assert!(!prev.track(&input, &BuildState::default()).unwrap());
+        let state_path = dir.path().join("state.json");
+        prev.save(&state_path).unwrap();
+        let prev = BuildState::load(&state_path);
+
+        let mut state = BuildState::default();
+        state.setting("arch", "sm_50");
+        assert!(state.track(&input, &prev).unwrap());
+        assert_eq!(state, prev);
+
+        fs::write(&input, "foobar").unwrap();
+        let mut state = BuildState::default();
+        assert!(!state.track(&input, &prev).unwrap());
+    }
+}
@@ -0,0 +1,117 @@
+//! Rust symbol names in bitcode and PTX
+//!
+//! Kernels without `#[no_mangle]` are exported with mangled names:
+//!
+//! ```
+//! use nvptx::symbol::*;
+//!
+//! let symbol = "_ZN6kernel3add17h3f5a7c8e2d1b9a04E";
+//! assert!(is_mangled(symbol));
+//! assert_eq!(demangle(symbol), "kernel::add");
+//! assert!(matches(symbol, "kernel::add"));
+//! assert_eq!(demangle("add"), "add");
+//! ```
+
+use glob::Pattern;
+use rustc_demangle::try_demangle;
+
+use crate::error::ResultAny;
+
+/// Check the symbol is mangled by rustc
+pub fn is_mangled(symbol: &str) -> bool {
+    try_demangle(symbol).is_ok()
+}
+
+/// Demangled path without hash, or the symbol itself if not mangled
+pub fn demangle(symbol: &str) -> String {
+    match try_demangle(symbol) {
+        Ok(name) => format!("{:#}", name),
+        Err(_) => symbol.to_string(),
+    }
+}
+
+/// Check the symbol is the name, which is either the symbol itself or its demangled path
+pub fn matches(symbol: &str, name: &str) -> bool {
+    symbol == name || (is_mangled(symbol) && demangle(symbol) == name)
+}
+
+/// Symbols resolved by the CUDA driver when PTX is loaded
+const CUDA_SYMBOLS: [&str; 4] = ["vprintf", "malloc", "free", "__assertfail"];
+
+/// Runtime crates linked as bitcode by `[package.metadata.nvptx] runtime`
+const RUNTIME_CRATES: [&str; 3] = ["core", "alloc", "compiler_builtins"];
+
+/// Check the symbol is provided by CUDA, e.g. `vprintf`
+pub fn is_cuda_symbol(symbol: &str) -> bool {
+    CUDA_SYMBOLS.contains(&symbol)
+}
+
+/// Runtime crate likely defining the symbol
+///
+/// ```
+/// use nvptx::symbol::runtime_crate;
+///
+/// assert_eq!(runtime_crate("_ZN4core9panicking5panic17h3f5a7c8e2d1b9a04E"), Some("core"));
+/// assert_eq!(runtime_crate("__rust_alloc"), Some("alloc"));
+/// assert_eq!(runtime_crate("__udivti3"), Some("compiler_builtins"));
+/// assert_eq!(runtime_crate("my_func"), None);
+/// ```
+pub fn runtime_crate(symbol: &str) -> Option<&'static str> {
+    if is_mangled(symbol) {
+        // e.g. `core::fmt::write` or `<core::fmt::Error as core::fmt::Debug>::fmt`
+        let path = demangle(symbol);
+        let path = path.trim_start_matches('<');
+        return RUNTIME_CRATES
+            .iter()
+            .find(|krate| path.starts_with(&format!("{}::", krate)))
+            .cloned();
+    }
+    if symbol.starts_with("__rust_") {
+        return Some("alloc");
+    }
+    let compiler_rt = ["memcpy", "memmove", "memset", "memcmp", "bcmp"].contains(&symbol)
+        || (symbol.starts_with("__") && symbol.ends_with(|c: char| c.is_ascii_digit()));
+    if compiler_rt {
+        Some("compiler_builtins")
+    } else {
+        None
+    }
+}
+
+/// Glob patterns selecting kernels exported in PTX
+///
+/// Patterns are matched with the symbol and its demangled path.
+/// A kernel is exported if it matches one of `include` (or `include` is empty),
+/// and does not match any of `exclude`:
+///
+/// ```
+/// use nvptx::symbol::KernelFilter;
+///
+/// let filter = KernelFilter::new(&[], &["accel_core::*".into()]).unwrap();
+/// assert!(filter.is_exported("add"));
+/// assert!(!filter.is_exported("_ZN10accel_core4fill17h3f5a7c8e2d1b9a04E"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct KernelFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl KernelFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> ResultAny<Self> {
+        let compile = |patterns: &[String]| -> ResultAny<Vec<Pattern>> {
+            patterns.iter().map(|p| Ok(Pattern::new(p)?)).collect()
+        };
+        Ok(KernelFilter {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    pub fn is_exported(&self, symbol: &str) -> bool {
+        let name = demangle(symbol);
+        let matched = |p: &Pattern| p.matches(symbol) || p.matches(&name);
+        (self.include.is_empty() || self.include.iter().any(matched))
+            && !self.exclude.iter().any(matched)
+    }
+}
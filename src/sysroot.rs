@@ -0,0 +1,255 @@
+//! Build sysroot for nvptx target from `rust-src` (xargo-style)
+//!
+//! A stock nightly toolchain with `rust-src` component can build runtime crates (`core`, `alloc`)
+//! for nvptx target. Built rlibs are placed in `lib/rustlib/{target}/lib` of the sysroot,
+//! and converted into LLVM bitcodes.
+//!
+//! The built sysroot is registered in `sysroots.json` of the data directory with the toolchain
+//! and target. `Driver` uses the sysroot registered for its toolchain unless one is set by
+//! `Driver::set_sysroot`, and `get_compiler_rt` searches the registered ones after the
+//! `accel-nvptx` toolchain.
+
+use colored::*;
+use failure::err_msg;
+use serde::{Deserialize, Serialize};
+use std::path::*;
+use std::{fs, process};
+use tempdir::TempDir;
+
+use super::save_str;
+use crate::driver::rlib2bc;
+use crate::error::ResultAny;
+use crate::llvm::Discovery;
+use crate::target::Target;
+use crate::toolchain::{get_toolchain_path, runtime_crate_name};
+
+/// Runtime crates which can be built from `rust-src`
+const RUNTIME_CRATES: [&str; 3] = ["core", "alloc", "compiler_builtins"];
+
+const STUB_MANIFEST: &str = r#"[package]
+name = "nvptx-sysroot"
+version = "0.0.0"
+edition = "2018"
+
+[lib]
+path = "lib.rs"
+"#;
+
+const STUB_LIB: &str = "#![no_std]\n";
+
+/// Data directory of nvptx, e.g. `~/.local/share/accel-nvptx`
+fn data_dir() -> ResultAny<PathBuf> {
+    dirs::data_dir()
+        .map(|dir| dir.join("accel-nvptx"))
+        .ok_or_else(|| err_msg("Data directory is not found. Please specify the sysroot path"))
+}
+
+/// Default path of the sysroot built by [Sysroot::build]
+pub fn default_path() -> ResultAny<PathBuf> {
+    Ok(data_dir()?.join("sysroot"))
+}
+
+/// File recording the sysroots built by [Sysroot::build]
+pub fn registry_path() -> ResultAny<PathBuf> {
+    Ok(data_dir()?.join("sysroots.json"))
+}
+
+/// Sysroot built by [Sysroot::build]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Registration {
+    pub toolchain: String,
+    pub target: String,
+    pub path: PathBuf,
+}
+
+/// Sysroots in the registry, empty if not exists
+pub fn registrations(registry: &Path) -> Vec<Registration> {
+    fs::read_to_string(registry)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// Record the sysroot, replacing the one for the same toolchain and target
+pub(crate) fn register(registry: &Path, reg: Registration) -> ResultAny<()> {
+    let mut regs = registrations(registry);
+    regs.retain(|r| r.toolchain != reg.toolchain || r.target != reg.target);
+    regs.push(reg);
+    if let Some(dir) = registry.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(registry, serde_json::to_string_pretty(&regs)?)?;
+    Ok(())
+}
+
+/// Registered sysroot for the target, built by the toolchain if specified.
+/// The last registered one is used if several match.
+pub fn find_registered(registry: &Path, toolchain: Option<&str>, target: &str) -> Option<PathBuf> {
+    registrations(registry)
+        .into_iter()
+        .rev()
+        .find(|r| {
+            r.target == target
+                && toolchain.map(|t| r.toolchain == t).unwrap_or(true)
+                && lib_dir(&r.path, target).is_dir()
+        })
+        .map(|r| r.path)
+}
+
+/// [find_registered] in the default registry
+pub fn registered(toolchain: Option<&str>, target: &str) -> Option<PathBuf> {
+    find_registered(&registry_path().ok()?, toolchain, target)
+}
+
+/// Directory where the runtime libraries for the target are placed
+pub fn lib_dir(sysroot: &Path, target: &str) -> PathBuf {
+    sysroot.join("lib/rustlib").join(target).join("lib")
+}
+
+/// Builder of nvptx sysroot
+pub struct Sysroot {
+    toolchain: String,
+    path: Option<PathBuf>,
+    target: Target,
+    crates: Vec<String>,
+    llvm: Discovery,
+}
+
+impl Sysroot {
+    /// Build `core` using the toolchain (e.g. `nightly`) into the default path
+    pub fn new(toolchain: &str) -> Self {
+        Sysroot {
+            toolchain: toolchain.into(),
+            path: None,
+            target: Target::default(),
+            crates: vec!["core".into(), "compiler_builtins".into()],
+            llvm: Discovery::default(),
+        }
+    }
+
+    pub fn set_path<P: AsRef<Path>>(&mut self, path: P) {
+        self.path = Some(path.as_ref().to_owned());
+    }
+
+    pub fn set_target(&mut self, target: Target) {
+        self.target = target;
+    }
+
+    /// Setting for resolving `llvm-link`, e.g. the one of `Driver`
+    pub fn set_llvm(&mut self, llvm: Discovery) {
+        self.llvm = llvm;
+    }
+
+    /// Build `alloc` in addition to `core` and `compiler_builtins`
+    pub fn with_alloc(&mut self) {
+        if !self.crates.iter().any(|c| c == "alloc") {
+            self.crates.push("alloc".into());
+        }
+    }
+
+    /// Runtime crates converted into LLVM bitcode (default: `core` and `compiler_builtins`)
+    pub fn set_crates(&mut self, crates: &[&str]) -> ResultAny<()> {
+        for name in crates {
+            if !RUNTIME_CRATES.contains(name) {
+                return Err(err_msg(format!(
+                    "{} cannot be built from rust-src (supported: {})",
+                    name,
+                    RUNTIME_CRATES.join(", ")
+                )));
+            }
+        }
+        self.crates = crates.iter().map(|s| s.to_string()).collect();
+        Ok(())
+    }
+
+    /// Install path of the sysroot, [default_path] if not set
+    pub fn path(&self) -> ResultAny<PathBuf> {
+        match &self.path {
+            Some(path) => Ok(path.clone()),
+            None => default_path(),
+        }
+    }
+
+    /// Build runtime crates, register the sysroot, and returns its path
+    pub fn build(&self) -> ResultAny<PathBuf> {
+        let path = self.path()?;
+        let rust_src = get_toolchain_path(&self.toolchain)?.join("lib/rustlib/src/rust");
+        if !rust_src.exists() {
+            return Err(err_msg(format!(
+                "rust-src is not found. Please run `rustup component add rust-src --toolchain {}`",
+                self.toolchain
+            )));
+        }
+
+        // Build stub crate with -Zbuild-std to compile runtime crates
+        let stub = TempDir::new("nvptx-sysroot")?;
+        save_str(stub.path(), STUB_MANIFEST, "Cargo.toml")?;
+        save_str(stub.path(), STUB_LIB, "lib.rs")?;
+        let std_crates = if self.crates.iter().any(|c| c == "alloc") {
+            "core,alloc"
+        } else {
+            "core"
+        };
+        eprintln!(
+            "{:>12} sysroot ({}) using {} toolchain",
+            "Compiling".bright_green(),
+            std_crates,
+            self.toolchain
+        );
+        let st = process::Command::new("cargo")
+            .arg(format!("+{}", self.toolchain))
+            .args(["build", "--release", "--target", &self.target.as_arg()])
+            .arg(format!("-Zbuild-std={}", std_crates))
+            .current_dir(stub.path())
+            .status()?;
+        if !st.success() {
+            return Err(err_msg("Failed to build runtime crates from rust-src"));
+        }
+
+        // Replace libraries in the sysroot
+        let lib_dir = lib_dir(&path, &self.target.name());
+        if lib_dir.exists() {
+            fs::remove_dir_all(&lib_dir)?;
+        }
+        fs::create_dir_all(&lib_dir)?;
+        let deps = stub
+            .path()
+            .join("target")
+            .join(self.target.name())
+            .join("release/deps");
+        let tools = self.llvm.resolve()?;
+        for entry in fs::read_dir(&deps)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext != "rlib").unwrap_or(true) {
+                continue;
+            }
+            let name = match runtime_crate_name(&path) {
+                Some(name) => name,
+                None => continue,
+            };
+            if name == "nvptx_sysroot" {
+                continue;
+            }
+            let rlib = lib_dir.join(path.file_name().unwrap());
+            fs::copy(&path, &rlib)?;
+            if self.crates.contains(&name) {
+                eprintln!("{:>12} {}", "Converting".bright_green(), rlib.display());
+                rlib2bc(&rlib, &tools.llvm_link)?;
+            }
+        }
+        register(
+            &registry_path()?,
+            Registration {
+                toolchain: self.toolchain.clone(),
+                target: self.target.name(),
+                path: path.clone(),
+            },
+        )?;
+        eprintln!(
+            "{:>12} sysroot ({})",
+            "Finished".bright_green(),
+            path.display()
+        );
+        Ok(path)
+    }
+}
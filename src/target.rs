@@ -0,0 +1,179 @@
+//! Compile target, built-in or custom target specification JSON
+//!
+//! ```text
+//! {
+//!   "llvm-target": "nvptx64-nvidia-cuda",
+//!   "data-layout": "e-i64:64-i128:128-v16:16-v32:32-n16:32:64",
+//!   "arch": "nvptx64",
+//!   ...
+//! }
+//! ```
+
+use serde::{Deserialize, Serialize};
+use serde_json::{self, Value};
+use std::collections::BTreeMap;
+use std::path::*;
+use std::{env, fs};
+
+use super::TARGET_NAME;
+use crate::error::ResultAny;
+
+/// Target passed to `cargo build --target`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    /// Built-in target of rustc, e.g. `nvptx64-nvidia-cuda`
+    Builtin(String),
+    /// Path of target specification JSON
+    Spec(PathBuf),
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::Builtin(TARGET_NAME.into())
+    }
+}
+
+impl Target {
+    /// Target name, or path to JSON file if it ends with `.json`
+    pub fn new(target: &str) -> Self {
+        if target.ends_with(".json") {
+            let path = Path::new(target);
+            if path.is_absolute() {
+                Target::Spec(path.to_owned())
+            } else {
+                Target::Spec(env::current_dir().unwrap().join(path))
+            }
+        } else {
+            Target::Builtin(target.into())
+        }
+    }
+
+    /// Target name used in output directory, e.g. `target/{name}/debug`.
+    /// Cargo uses the file stem for target specification JSON.
+    pub fn name(&self) -> String {
+        match self {
+            Target::Builtin(name) => name.clone(),
+            Target::Spec(path) => path.file_stem().unwrap().to_str().unwrap().into(),
+        }
+    }
+
+    /// Argument of `--target`
+    pub fn as_arg(&self) -> String {
+        match self {
+            Target::Builtin(name) => name.clone(),
+            Target::Spec(path) => path.to_str().unwrap().into(),
+        }
+    }
+}
+
+/// Target specification JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TargetSpec {
+    pub llvm_target: String,
+    pub data_layout: String,
+    pub arch: String,
+    pub os: String,
+    pub target_endian: String,
+    pub target_pointer_width: String,
+    pub target_c_int_width: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub features: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub panic_strategy: Option<String>,
+    /// Other keys, kept as is
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl Default for TargetSpec {
+    fn default() -> Self {
+        Self::nvptx64()
+    }
+}
+
+impl TargetSpec {
+    /// Specification equivalent to built-in `nvptx64-nvidia-cuda`
+    pub fn nvptx64() -> Self {
+        Self::cuda(
+            "nvptx64-nvidia-cuda",
+            "nvptx64",
+            "e-i64:64-i128:128-v16:16-v32:32-n16:32:64",
+            "64",
+        )
+    }
+
+    /// Specification for 32-bit `nvptx-nvidia-cuda`
+    pub fn nvptx() -> Self {
+        Self::cuda(
+            "nvptx-nvidia-cuda",
+            "nvptx",
+            "e-p:32:32-i64:64-i128:128-v16:16-v32:32-n16:32:64",
+            "32",
+        )
+    }
+
+    fn cuda(llvm_target: &str, arch: &str, data_layout: &str, pointer_width: &str) -> Self {
+        let mut extra = BTreeMap::new();
+        extra.insert("linker-flavor".into(), "ptx".into());
+        extra.insert("max-atomic-width".into(), 64.into());
+        extra.insert("obj-is-bitcode".into(), true.into());
+        extra.insert("dynamic-linking".into(), false.into());
+        extra.insert("executables".into(), true.into());
+        TargetSpec {
+            llvm_target: llvm_target.into(),
+            data_layout: data_layout.into(),
+            arch: arch.into(),
+            os: "cuda".into(),
+            target_endian: "little".into(),
+            target_pointer_width: pointer_width.into(),
+            target_c_int_width: "32".into(),
+            cpu: Some("sm_30".into()),
+            features: None,
+            panic_strategy: Some("abort".into()),
+            extra,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> ResultAny<Self> {
+        let json = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Save as `{name}.json` in the directory, and returns corresponding `Target`
+    pub fn save<P: AsRef<Path>>(&self, dir: P, name: &str) -> ResultAny<Target> {
+        let path = dir.as_ref().join(format!("{}.json", name));
+        fs::write(&path, self.as_json())?;
+        Ok(Target::Spec(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_name() {
+        assert_eq!(Target::default().name(), "nvptx64-nvidia-cuda");
+        let spec = Target::new("/tmp/nvptx-custom.json");
+        assert_eq!(spec, Target::Spec("/tmp/nvptx-custom.json".into()));
+        assert_eq!(spec.name(), "nvptx-custom");
+        assert_eq!(spec.as_arg(), "/tmp/nvptx-custom.json");
+    }
+
+    #[test]
+    fn spec_roundtrip() {
+        let spec = TargetSpec::nvptx();
+        let json = spec.as_json();
+        assert!(json.contains(r#""llvm-target": "nvptx-nvidia-cuda""#));
+        assert!(json.contains(r#""obj-is-bitcode": true"#));
+        let spec2: TargetSpec = serde_json::from_str(&json).unwrap();
+        assert_eq!(spec, spec2);
+    }
+}
@@ -0,0 +1,63 @@
+//! Wall-clock timings of each stage of the driver
+
+use std::time::Duration;
+
+/// Elapsed time of each stage, `None` if the stage is not executed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timings {
+    /// `cargo build`
+    pub build: Option<Duration>,
+    /// Conversion from rlib into LLVM bitcode
+    pub rlib2bc: Option<Duration>,
+    /// `llvm-link`
+    pub link: Option<Duration>,
+    /// `opt -internalize -globaldce`
+    pub internalize: Option<Duration>,
+    /// `llc`
+    pub codegen: Option<Duration>,
+    /// `nvcc --cubin`
+    pub cubin: Option<Duration>,
+}
+
+impl Timings {
+    /// Executed stages and its elapsed time
+    pub fn stages(&self) -> Vec<(&'static str, Duration)> {
+        [
+            ("build", self.build),
+            ("rlib2bc", self.rlib2bc),
+            ("link", self.link),
+            ("internalize", self.internalize),
+            ("codegen", self.codegen),
+            ("cubin", self.cubin),
+        ]
+        .iter()
+        .filter_map(|&(name, t)| Some((name, t?)))
+        .collect()
+    }
+
+    pub fn total(&self) -> Duration {
+        self.stages().iter().map(|(_, t)| *t).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stages() {
+        let timings = Timings {
+            build: Some(Duration::from_millis(300)),
+            codegen: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        assert_eq!(
+            timings.stages(),
+            vec![
+                ("build", Duration::from_millis(300)),
+                ("codegen", Duration::from_millis(20))
+            ]
+        );
+        assert_eq!(timings.total(), Duration::from_millis(320));
+    }
+}
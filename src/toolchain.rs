@@ -1,18 +1,23 @@
 use failure::err_msg;
+use std::collections::BTreeMap;
 use std::path::*;
 use std::str::from_utf8;
 use std::{fs, process};
 use tempdir::TempDir;
 
 use super::{TARGET_NAME, TOOLCHAIN_NAME};
-use crate::driver::rlib2bc;
+use crate::driver::rlib2bc_all;
 use crate::error::ResultAny;
+use crate::llvm::Discovery;
+use crate::sysroot;
 
 /// Download nvptx-enable rustc from AWS S3
 ///
 /// This archive has been generated from rust-accel/rust fork
 /// https://github.com/rust-accel/rust
-pub fn install(path: &Path) -> ResultAny<()> {
+///
+/// rlibs are converted into LLVM bitcode using `jobs` threads.
+pub fn install(path: &Path, jobs: usize) -> ResultAny<()> {
     fs::create_dir_all(path)?;
     let tmp_dir = TempDir::new("nvptx_install")?;
     let rustc = "rustc";
@@ -71,79 +76,142 @@ pub fn install(path: &Path) -> ResultAny<()> {
     }
 
     // Expand rlib into LLVM BC, and link them
-    let nvptx_dir = get_nvptx_lib_path()?;
+    let tools = Discovery::default().resolve()?;
+    let nvptx_dir = get_nvptx_lib_path(TARGET_NAME)?;
     eprintln!("Convert rlibs in {}", nvptx_dir.display());
+    let mut rlibs = Vec::new();
     for entry in fs::read_dir(&nvptx_dir)? {
         let path = entry?.path();
         if path.extension().unwrap() == "rlib" {
             eprintln!(" - {}", path.display());
-            rlib2bc(&path)?;
+            rlibs.push(path);
         }
     }
+    rlib2bc_all(&rlibs, &tools.llvm_link, jobs)?;
     Ok(())
 }
 
-fn get_toolchain_path() -> ResultAny<PathBuf> {
+pub(crate) fn get_toolchain_path(toolchain: &str) -> ResultAny<PathBuf> {
     let output = process::Command::new("rustup")
-        .args(&["run", TOOLCHAIN_NAME, "rustc", "--print", "sysroot"])
+        .args(["run", toolchain, "rustc", "--print", "sysroot"])
         .output()?;
+    if !output.status.success() {
+        return Err(err_msg(format!("Toolchain {} is not found", toolchain)));
+    }
     Ok(PathBuf::from(from_utf8(&output.stdout)?.trim()))
 }
 
-fn get_nvptx_lib_path() -> ResultAny<PathBuf> {
-    Ok(get_toolchain_path()?
-        .join("lib/rustlib")
-        .join(TARGET_NAME)
-        .join("lib"))
+fn get_nvptx_lib_path(target: &str) -> ResultAny<PathBuf> {
+    Ok(sysroot::lib_dir(
+        &get_toolchain_path(TOOLCHAIN_NAME)?,
+        target,
+    ))
+}
+
+/// Directories searched for runtime bitcodes, in priority order
+///
+/// - sysroot built by `Sysroot::build`, if specified
+/// - `accel-nvptx` toolchain installed by `install`
+/// - sysroot registered by `Sysroot::build`, if not specified
+pub(crate) fn runtime_dirs(sysroot: Option<&Path>, target: &str) -> Vec<PathBuf> {
+    runtime_dirs_in(sysroot, sysroot::registry_path().ok().as_deref(), target)
+}
+
+fn runtime_dirs_in(sysroot: Option<&Path>, registry: Option<&Path>, target: &str) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = sysroot
+        .map(|sysroot| sysroot::lib_dir(sysroot, target))
+        .into_iter()
+        .collect();
+    if let Ok(dir) = get_nvptx_lib_path(target) {
+        dirs.push(dir);
+    }
+    if sysroot.is_none() {
+        if let Some(registered) = registry.and_then(|r| sysroot::find_registered(r, None, target)) {
+            dirs.push(sysroot::lib_dir(&registered, target));
+        }
+    }
+    dirs
+}
+
+/// Runtime bitcodes in the sysroots, keyed by crate name
+///
+/// e.g. `libcore-8c9f9b9d0e2a1b3c.bc` is registered as `core`.
+/// A crate name may have several bitcodes if the sysroot contains stale ones.
+pub fn get_all_compiler_rt() -> ResultAny<BTreeMap<String, Vec<PathBuf>>> {
+    collect_compiler_rt(&runtime_dirs(None, TARGET_NAME))
 }
 
-fn get_all_compiler_rt() -> ResultAny<Vec<PathBuf>> {
-    let nvptx_dir = get_nvptx_lib_path()?;
-    Ok(fs::read_dir(&nvptx_dir)?
-        .filter_map(|entry| {
-            let path = entry.unwrap().path();
-            if path.extension()? != "bc" {
-                return None;
+/// Collect runtime bitcodes in the directories.
+/// A crate found in the prior directory hides the same crate in the latter directories.
+pub(crate) fn collect_compiler_rt(dirs: &[PathBuf]) -> ResultAny<BTreeMap<String, Vec<PathBuf>>> {
+    let mut all = BTreeMap::new();
+    let mut found_dir = false;
+    for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+        found_dir = true;
+        let mut rt: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext != "bc").unwrap_or(true) {
+                continue;
             }
-            Some(path)
-        })
-        .collect())
+            if let Some(name) = runtime_crate_name(&path) {
+                rt.entry(name).or_default().push(path);
+            }
+        }
+        for (name, paths) in rt {
+            all.entry(name).or_insert(paths);
+        }
+    }
+    if !found_dir {
+        return Err(err_msg(format!(
+            "No runtime library directory found (searched: {:?}). Run `nvptx install` or `nvptx sysroot`",
+            dirs
+        )));
+    }
+    Ok(all)
 }
 
-/// Installed runtime libraries
-const RUNTIME_LIBS: [&str; 13] = [
-    "alloc",
-    "alloc_system",
-    "compiler_builtins",
-    "core",
-    "getopts",
-    "libc",
-    "panic_abort",
-    "panic_unwind",
-    "std",
-    "std_unicode",
-    "term",
-    "test",
-    "unwind",
-];
+/// Crate name of a runtime library, e.g. `core` for `libcore-8c9f9b9d0e2a1b3c.bc`
+pub(crate) fn runtime_crate_name(path: &Path) -> Option<String> {
+    let stem = path.file_stem()?.to_str()?;
+    let stem = stem.strip_prefix("lib").unwrap_or(stem);
+    let name = match stem.rfind('-') {
+        Some(pos) if is_crate_hash(&stem[pos + 1..]) => &stem[..pos],
+        _ => stem,
+    };
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
 
+fn is_crate_hash(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Get the bitcodes of runtime libraries, e.g. `["core", "alloc"]`
 pub fn get_compiler_rt(runtimes: &[String]) -> ResultAny<Vec<PathBuf>> {
-    let all = get_all_compiler_rt()?;
-    Ok(runtimes
+    find_compiler_rt(&runtime_dirs(None, TARGET_NAME), runtimes)
+}
+
+pub(crate) fn find_compiler_rt(dirs: &[PathBuf], runtimes: &[String]) -> ResultAny<Vec<PathBuf>> {
+    let all = collect_compiler_rt(dirs)?;
+    runtimes
         .iter()
-        .filter_map(|rt| {
-            if !RUNTIME_LIBS.contains(&rt.as_str()) {
-                eprintln!("Runtime not supported: {}", rt);
-                return None;
-            }
-            for path in &all {
-                if path.file_stem()?.to_str()?.contains(rt) {
-                    return Some(path.clone());
-                }
-            }
-            unreachable!("Corresponding BC does not found");
+        .map(|rt| match all.get(rt).map(|paths| paths.as_slice()) {
+            Some([path]) => Ok(path.clone()),
+            Some(paths) => Err(err_msg(format!(
+                "Multiple bitcodes found for runtime {}: {:?}",
+                rt, paths
+            ))),
+            None => Err(err_msg(format!(
+                "Runtime {} is not found in sysroot (available: {})",
+                rt,
+                all.keys().cloned().collect::<Vec<_>>().join(", ")
+            ))),
         })
-        .collect())
+        .collect()
 }
 
 #[cfg(test)]
@@ -154,7 +222,51 @@ mod tests {
     fn all_compiler_rt() {
         let rt = get_all_compiler_rt().unwrap();
         println!("Compiler runtimes = {:?}", rt);
-        assert_eq!(rt.len(), 13);
+        assert!(rt.contains_key("core"));
+    }
+
+    #[test]
+    fn crate_name() {
+        let name = |s: &str| runtime_crate_name(Path::new(s));
+        assert_eq!(name("libcore-8c9f9b9d0e2a1b3c.bc"), Some("core".into()));
+        assert_eq!(
+            name("libcompiler_builtins-0123abcd.bc"),
+            Some("compiler_builtins".into())
+        );
+        assert_eq!(name("liballoc.bc"), Some("alloc".into()));
+        assert_eq!(name("lib.bc"), None);
+    }
+
+    #[test]
+    fn sysroot_dir() {
+        let target = "nvptx64-nvidia-cuda";
+        let dir = tempdir::TempDir::new("nvptx-sysroot").unwrap();
+        let registry = dir.path().join("sysroots.json");
+        let built = dir.path().join("sysroot");
+        fs::create_dir_all(sysroot::lib_dir(&built, target)).unwrap();
+        let reg = |toolchain: &str| sysroot::Registration {
+            toolchain: toolchain.into(),
+            target: target.into(),
+            path: built.clone(),
+        };
+        sysroot::register(&registry, reg("nightly")).unwrap();
+        sysroot::register(&registry, reg("nightly")).unwrap();
+        assert_eq!(sysroot::registrations(&registry), vec![reg("nightly")]);
+        assert_eq!(
+            sysroot::find_registered(&registry, Some("nightly"), target),
+            Some(built.clone())
+        );
+        assert_eq!(
+            sysroot::find_registered(&registry, Some("accel-nvptx"), target),
+            None
+        );
+
+        let dirs = runtime_dirs_in(None, Some(&registry), target);
+        assert_eq!(dirs.last(), Some(&sysroot::lib_dir(&built, target)));
+        // A specified sysroot is searched first instead of the registered one
+        let dirs = runtime_dirs_in(Some(Path::new("/tmp/sysroot")), Some(&registry), target);
+        assert_eq!(dirs[0], sysroot::lib_dir(Path::new("/tmp/sysroot"), target));
+        assert!(!dirs.contains(&sysroot::lib_dir(&built, target)));
     }
 
     #[test]